@@ -0,0 +1,165 @@
+use std::collections::{HashMap, HashSet};
+
+use auth::{self, Event, EventReference};
+use intern::EventHandle;
+use persistent_state_map::PersistentStateMap;
+use room_version::RoomVersion;
+use state_map::StateMap;
+
+/// Returns the auth chain of the given events, i.e. every event reachable
+/// by following `auth_events`.
+///
+/// Events that are referenced but missing from the event map are included,
+/// but their auth events obviously aren't.
+pub fn get_auth_chain<I>(
+    event_ids: I,
+    event_map: &HashMap<EventHandle, Event>,
+) -> HashSet<EventHandle>
+where
+    I: IntoIterator<Item = EventHandle>,
+{
+    let mut chain = HashSet::new();
+
+    let mut stack: Vec<EventHandle> = event_ids
+        .into_iter()
+        .filter_map(|eid| event_map.get(&eid))
+        .flat_map(|ev| ev.auth_events().iter().map(EventReference::handle))
+        .collect();
+
+    while let Some(eid) = stack.pop() {
+        if !chain.insert(eid) {
+            continue;
+        }
+
+        if let Some(event) = event_map.get(&eid) {
+            stack.extend(event.auth_events().iter().map(EventReference::handle));
+        }
+    }
+
+    chain
+}
+
+/// Returns the events that appear in some but not all of the auth chains of
+/// the state sets. The auth chain of a state set includes the state events
+/// themselves.
+pub fn auth_chain_difference(
+    state_sets: &[&StateMap<EventHandle>],
+    event_map: &HashMap<EventHandle, Event>,
+) -> HashSet<EventHandle> {
+    let chains: Vec<HashSet<EventHandle>> = state_sets
+        .iter()
+        .map(|state| {
+            let mut chain = get_auth_chain(state.values().cloned(), event_map);
+
+            chain.extend(
+                state
+                    .values()
+                    .filter(|eid| event_map.contains_key(eid))
+                    .cloned(),
+            );
+
+            chain
+        })
+        .collect();
+
+    let mut union = HashSet::new();
+    for chain in &chains {
+        union.extend(chain.iter().cloned());
+    }
+
+    union
+        .into_iter()
+        .filter(|eid| !chains.iter().all(|chain| chain.contains(eid)))
+        .collect()
+}
+
+/// Builds a state map of the event's auth events.
+pub fn get_auth_events<'a>(
+    event: &Event,
+    event_map: &'a HashMap<EventHandle, Event>,
+) -> StateMap<&'a Event> {
+    event
+        .auth_events()
+        .iter()
+        .filter_map(|a| EventHandle::get(a.event_id()).and_then(|eid| event_map.get(&eid)))
+        .filter_map(|ev| {
+            ev.state_key
+                .as_ref()
+                .map(|s| ((&ev.etype as &str, s as &str), ev))
+        })
+        .collect()
+}
+
+/// Builds a state map of the events in the state that the auth rules would
+/// use for the event, plus the room's server ACL, which servers apply to
+/// incoming events.
+pub fn get_auth_events_from_state<'a>(
+    event: &Event,
+    state: &PersistentStateMap<EventHandle>,
+    event_map: &'a HashMap<EventHandle, Event>,
+    room_version: &RoomVersion,
+) -> StateMap<&'a Event> {
+    let mut auth_events = StateMap::new();
+
+    for (t, s) in auth::auth_types_for_event(event, room_version) {
+        if let Some(ev) = state.get(&t, &s).and_then(|e| event_map.get(e)) {
+            auth_events.insert(&t, &s, ev);
+        }
+    }
+
+    if let Some(ev) = state
+        .get("m.room.server_acl", "")
+        .and_then(|e| event_map.get(e))
+    {
+        auth_events.insert("m.room.server_acl", "", ev);
+    }
+
+    auth_events
+}
+
+#[test]
+fn test_auth_chain() {
+    use serde_json;
+
+    let lines = r#"
+{"event_id": "$create", "sender": "@a:a", "room_id": "!r:a", "type": "m.room.create", "state_key": "", "prev_events": [], "auth_events": [], "content": {}, "depth": 1}
+{"event_id": "$join", "sender": "@a:a", "room_id": "!r:a", "type": "m.room.member", "state_key": "@a:a", "prev_events": [["$create", {}]], "auth_events": [["$create", {}]], "content": {"membership": "join"}, "depth": 2}
+{"event_id": "$pl", "sender": "@a:a", "room_id": "!r:a", "type": "m.room.power_levels", "state_key": "", "prev_events": [["$join", {}]], "auth_events": [["$create", {}], ["$join", {}]], "content": {}, "depth": 3}
+{"event_id": "$topic1", "sender": "@a:a", "room_id": "!r:a", "type": "m.room.topic", "state_key": "", "prev_events": [["$pl", {}]], "auth_events": [["$create", {}], ["$join", {}], ["$pl", {}]], "content": {}, "depth": 4}
+{"event_id": "$topic2", "sender": "@a:a", "room_id": "!r:a", "type": "m.room.topic", "state_key": "", "prev_events": [["$join", {}]], "auth_events": [["$create", {}], ["$join", {}]], "content": {}, "depth": 3}
+"#;
+
+    let event_map: HashMap<EventHandle, Event> = lines
+        .trim()
+        .lines()
+        .map(|line| serde_json::from_str::<Event>(line).unwrap())
+        .map(|ev| (ev.handle(), ev))
+        .collect();
+
+    let handles = |eids: Vec<&str>| -> HashSet<EventHandle> {
+        eids.into_iter().map(EventHandle::intern).collect()
+    };
+
+    let chain = get_auth_chain(vec![EventHandle::intern("$topic1")], &event_map);
+    assert_eq!(chain, handles(vec!["$create", "$join", "$pl"]));
+
+    let state_a: StateMap<EventHandle> = vec![
+        (("m.room.create", ""), EventHandle::intern("$create")),
+        (("m.room.member", "@a:a"), EventHandle::intern("$join")),
+        (("m.room.power_levels", ""), EventHandle::intern("$pl")),
+        (("m.room.topic", ""), EventHandle::intern("$topic1")),
+    ]
+    .into_iter()
+    .collect();
+
+    let state_b: StateMap<EventHandle> = vec![
+        (("m.room.create", ""), EventHandle::intern("$create")),
+        (("m.room.member", "@a:a"), EventHandle::intern("$join")),
+        (("m.room.topic", ""), EventHandle::intern("$topic2")),
+    ]
+    .into_iter()
+    .collect();
+
+    let difference = auth_chain_difference(&[&state_a, &state_b], &event_map);
+    assert_eq!(difference, handles(vec!["$pl", "$topic1", "$topic2"]));
+}
@@ -0,0 +1,69 @@
+//! Matrix canonical JSON: object keys sorted by codepoint, no insignificant
+//! whitespace, and only integers in the range [-(2^53)+1, (2^53)-1].
+
+use failure::Error;
+use serde_json::{self, Value};
+
+/// The largest integer that canonical JSON allows, 2^53 - 1.
+pub const MAX_SAFE_INT: i64 = 9_007_199_254_740_991;
+
+/// The smallest integer that canonical JSON allows, -(2^53) + 1.
+pub const MIN_SAFE_INT: i64 = -MAX_SAFE_INT;
+
+/// Encodes the value as canonical JSON, failing if it contains floats or out
+/// of range integers.
+pub fn encode_canonical_json(value: &Value) -> Result<String, Error> {
+    check_canonical_json(value)?;
+
+    // serde_json maps are sorted by key, and its compact output doesn't add
+    // any whitespace or escape anything the spec doesn't.
+    Ok(serde_json::to_string(value)?)
+}
+
+/// Checks that every number in the value is an integer in the allowed range.
+pub fn check_canonical_json(value: &Value) -> Result<(), Error> {
+    match *value {
+        Value::Number(ref n) => {
+            let i = n
+                .as_i64()
+                .ok_or_else(|| format_err!("number {} is not a valid integer", n))?;
+            ensure!(
+                (MIN_SAFE_INT..=MAX_SAFE_INT).contains(&i),
+                "integer {} is out of range",
+                i
+            );
+        }
+        Value::Array(ref values) => {
+            for v in values {
+                check_canonical_json(v)?;
+            }
+        }
+        Value::Object(ref map) => {
+            for v in map.values() {
+                check_canonical_json(v)?;
+            }
+        }
+        Value::Null | Value::Bool(_) | Value::String(_) => {}
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_encode_canonical_json() {
+    let value: Value = serde_json::from_str(
+        r#"{"b": "é\n", "a": [1, {"d": null, "c": true}], "0": -9007199254740991}"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        encode_canonical_json(&value).unwrap(),
+        "{\"0\":-9007199254740991,\"a\":[1,{\"c\":true,\"d\":null}],\"b\":\"\u{e9}\\n\"}"
+    );
+
+    let value: Value = serde_json::from_str(r#"{"a": 9007199254740992}"#).unwrap();
+    assert!(encode_canonical_json(&value).is_err());
+
+    let value: Value = serde_json::from_str(r#"{"a": [1.5]}"#).unwrap();
+    assert!(encode_canonical_json(&value).is_err());
+}
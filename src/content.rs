@@ -0,0 +1,285 @@
+//! Typed content of the events that the auth rules depend on.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use serde_json::{self, Value};
+
+use auth::{AuthError, Event};
+
+/// The content of an event, parsed according to its type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventContent {
+    Create(CreateContent),
+    Member(MembershipContent),
+    JoinRules(JoinRulesContent),
+    ThirdPartyInvite(ThirdPartyInviteContent),
+    /// An event whose content the auth rules don't look at.
+    Other,
+}
+
+impl EventContent {
+    pub fn from_event(event: &Event) -> Result<EventContent, AuthError> {
+        let content = &event.content;
+
+        let parsed = match (&event.etype as &str, event.state_key.is_some()) {
+            ("m.room.create", true) => EventContent::Create(CreateContent::from_content(content)?),
+            ("m.room.member", true) => {
+                EventContent::Member(MembershipContent::from_content(content)?)
+            }
+            ("m.room.join_rules", true) => {
+                EventContent::JoinRules(JoinRulesContent::from_content(content))
+            }
+            ("m.room.third_party_invite", true) => {
+                EventContent::ThirdPartyInvite(ThirdPartyInviteContent::from_content(content))
+            }
+            _ => EventContent::Other,
+        };
+
+        Ok(parsed)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CreateContent {
+    /// The explicit creator, which is ignored from v11.
+    pub creator: Option<String>,
+    /// Whether servers other than the creator's may join, i.e. `m.federate`.
+    pub federate: bool,
+    pub room_version: Option<String>,
+}
+
+impl CreateContent {
+    pub fn from_content(
+        content: &serde_json::Map<String, Value>,
+    ) -> Result<CreateContent, AuthError> {
+        let room_version = match content.get("room_version") {
+            None => None,
+            Some(Value::String(room_version)) => Some(room_version.clone()),
+            Some(_) => return Err(AuthError::InvalidRoomVersion),
+        };
+
+        Ok(CreateContent {
+            creator: content
+                .get("creator")
+                .and_then(Value::as_str)
+                .map(String::from),
+            federate: content.get("m.federate") != Some(&Value::Bool(false)),
+            room_version,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Membership {
+    Join,
+    Invite,
+    Leave,
+    Ban,
+    Knock,
+}
+
+impl FromStr for Membership {
+    type Err = AuthError;
+
+    fn from_str(membership: &str) -> Result<Membership, AuthError> {
+        match membership {
+            "join" => Ok(Membership::Join),
+            "invite" => Ok(Membership::Invite),
+            "leave" => Ok(Membership::Leave),
+            "ban" => Ok(Membership::Ban),
+            "knock" => Ok(Membership::Knock),
+            _ => Err(AuthError::UnknownMembership(membership.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MembershipContent {
+    pub membership: Membership,
+    pub join_authorised_via_users_server: Option<String>,
+    /// The signed block of a third party invite. Only parsed for invites.
+    pub third_party_invite: Option<SignedThirdPartyInvite>,
+}
+
+impl MembershipContent {
+    pub fn from_content(
+        content: &serde_json::Map<String, Value>,
+    ) -> Result<MembershipContent, AuthError> {
+        let membership = content
+            .get("membership")
+            .and_then(Value::as_str)
+            .ok_or(AuthError::MissingMembership)
+            .and_then(str::parse)?;
+
+        let third_party_invite = match (membership, content.get("third_party_invite")) {
+            (Membership::Invite, Some(third_party_invite)) => {
+                let signed = third_party_invite
+                    .get("signed")
+                    .and_then(Value::as_object)
+                    .ok_or(AuthError::InvalidThirdPartyInvite)?;
+                Some(SignedThirdPartyInvite::from_json(signed)?)
+            }
+            _ => None,
+        };
+
+        Ok(MembershipContent {
+            membership,
+            join_authorised_via_users_server: content
+                .get("join_authorised_via_users_server")
+                .and_then(Value::as_str)
+                .map(String::from),
+            third_party_invite,
+        })
+    }
+}
+
+/// The `signed` block of a third party invite.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedThirdPartyInvite {
+    pub mxid: String,
+    pub token: String,
+    pub signatures: HashMap<String, HashMap<String, String>>,
+    /// The original JSON, which the signatures are over.
+    pub json: serde_json::Map<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct SignedFields {
+    mxid: String,
+    token: String,
+    signatures: HashMap<String, HashMap<String, String>>,
+}
+
+impl SignedThirdPartyInvite {
+    fn from_json(
+        json: &serde_json::Map<String, Value>,
+    ) -> Result<SignedThirdPartyInvite, AuthError> {
+        let fields: SignedFields = serde_json::from_value(Value::Object(json.clone()))
+            .map_err(|_| AuthError::InvalidThirdPartyInvite)?;
+
+        Ok(SignedThirdPartyInvite {
+            mxid: fields.mxid,
+            token: fields.token,
+            signatures: fields.signatures,
+            json: json.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinRule {
+    Public,
+    Invite,
+    Knock,
+    Restricted,
+    KnockRestricted,
+    /// A join rule we don't know, e.g. the reserved `private`.
+    Custom(String),
+}
+
+impl JoinRule {
+    pub fn as_str(&self) -> &str {
+        match *self {
+            JoinRule::Public => "public",
+            JoinRule::Invite => "invite",
+            JoinRule::Knock => "knock",
+            JoinRule::Restricted => "restricted",
+            JoinRule::KnockRestricted => "knock_restricted",
+            JoinRule::Custom(ref join_rule) => join_rule,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinRulesContent {
+    pub join_rule: JoinRule,
+}
+
+impl JoinRulesContent {
+    /// Parses the content, treating a missing join rule as `invite`.
+    pub fn from_content(content: &serde_json::Map<String, Value>) -> JoinRulesContent {
+        let join_rule = match content.get("join_rule").and_then(Value::as_str) {
+            None | Some("invite") => JoinRule::Invite,
+            Some("public") => JoinRule::Public,
+            Some("knock") => JoinRule::Knock,
+            Some("restricted") => JoinRule::Restricted,
+            Some("knock_restricted") => JoinRule::KnockRestricted,
+            Some(join_rule) => JoinRule::Custom(join_rule.to_string()),
+        };
+
+        JoinRulesContent { join_rule }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThirdPartyInviteContent {
+    /// The keys from both `public_key` and `public_keys`.
+    pub public_keys: Vec<String>,
+}
+
+impl ThirdPartyInviteContent {
+    pub fn from_content(content: &serde_json::Map<String, Value>) -> ThirdPartyInviteContent {
+        let mut public_keys: Vec<String> = content
+            .get("public_key")
+            .and_then(Value::as_str)
+            .map(String::from)
+            .into_iter()
+            .collect();
+
+        if let Some(keys) = content.get("public_keys").and_then(Value::as_array) {
+            public_keys.extend(
+                keys.iter()
+                    .filter_map(|k| k.get("public_key"))
+                    .filter_map(Value::as_str)
+                    .map(String::from),
+            );
+        }
+
+        ThirdPartyInviteContent { public_keys }
+    }
+}
+
+#[test]
+fn test_event_content() {
+    let parse = |etype: &str, content: &str| {
+        let event: Event = serde_json::from_str(&format!(
+            r#"{{"sender": "@a:a", "room_id": "!r:a", "event_id": "$1:a", "type": "{}",
+            "state_key": "", "prev_events": [], "content": {}, "depth": 1}}"#,
+            etype, content
+        ))
+        .unwrap();
+        EventContent::from_event(&event)
+    };
+
+    assert_eq!(
+        parse("m.room.create", r#"{"creator": "@a:a", "m.federate": false}"#),
+        Ok(EventContent::Create(CreateContent {
+            creator: Some("@a:a".into()),
+            federate: false,
+            room_version: None,
+        }))
+    );
+    assert_eq!(
+        parse("m.room.create", r#"{"room_version": 1}"#),
+        Err(AuthError::InvalidRoomVersion)
+    );
+
+    assert_eq!(
+        parse("m.room.member", r#"{"membership": "joined"}"#),
+        Err(AuthError::UnknownMembership("joined".into()))
+    );
+    assert_eq!(
+        parse("m.room.member", r#"{"membership": "invite", "third_party_invite": {}}"#),
+        Err(AuthError::InvalidThirdPartyInvite)
+    );
+
+    assert_eq!(
+        parse("m.room.join_rules", r#"{"join_rule": "private"}"#),
+        Ok(EventContent::JoinRules(JoinRulesContent {
+            join_rule: JoinRule::Custom("private".into()),
+        }))
+    );
+
+    assert_eq!(parse("m.room.message", r#"{"body": 1}"#), Ok(EventContent::Other));
+}
@@ -0,0 +1,68 @@
+use std::cmp::Ordering;
+
+use postgres;
+
+use intern::EventHandle;
+use room::StateGroups;
+use state_map::StateMap;
+
+/// Fetch the state at the given event from a Synapse database.
+pub fn get_state(conn: &postgres::Connection, event_id: &str) -> StateMap<EventHandle> {
+    let q = conn.query(GET_STATE_QUERY, &[&event_id]).unwrap();
+
+    q.iter()
+        .map(|row| {
+            let etype: String = row.get(0);
+            let state_key: String = row.get(1);
+            let event_id: String = row.get(2);
+            ((etype, state_key), EventHandle::intern(&event_id))
+        })
+        .collect()
+}
+
+const GET_STATE_QUERY: &str = r#"
+    WITH RECURSIVE state(state_group) AS (
+        SELECT state_group FROM event_to_state_groups WHERE event_id = $1
+        UNION ALL
+        SELECT prev_state_group FROM state_group_edges e, state s
+        WHERE s.state_group = e.state_group
+    )
+    SELECT DISTINCT type, state_key, last_value(event_id) OVER (
+        PARTITION BY type, state_key ORDER BY state_group ASC
+        ROWS BETWEEN UNBOUNDED PRECEDING AND UNBOUNDED FOLLOWING
+    ) AS event_id FROM state_groups_state
+    WHERE state_group IN (
+        SELECT state_group FROM state
+    )
+"#;
+
+/// Binary search for the first event in `ordered` where our calculated state
+/// diverges from what's in the database. Returns its index in `ordered`.
+pub fn find_first_divergence(
+    conn: &postgres::Connection,
+    ordered: &[EventHandle],
+    groups: &StateGroups,
+) -> Option<usize> {
+    let res = ordered.binary_search_by(|event_id| {
+        let state = groups
+            .get_state(*event_id)
+            .expect("state was calculated")
+            .to_state_map();
+
+        let actual = get_state(conn, event_id.as_str());
+
+        if actual.diff(&state).is_empty() {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        }
+    });
+
+    let i = res.unwrap_err();
+
+    if i < ordered.len() {
+        Some(i)
+    } else {
+        None
+    }
+}
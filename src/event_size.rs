@@ -0,0 +1,80 @@
+//! Checks on the size of events and their fields.
+
+use serde_json;
+
+use auth::Event;
+use room_version::{EventFormat, RoomVersion};
+
+/// The maximum size of an event in bytes, as canonical JSON.
+pub const MAX_EVENT_SIZE: usize = 65536;
+
+/// The maximum size of the `sender`, `room_id`, `state_key`, `type` and
+/// `event_id` fields in bytes.
+pub const MAX_FIELD_SIZE: usize = 255;
+
+/// A size limit the event exceeds.
+#[derive(Debug, Clone, PartialEq, Eq, Fail)]
+pub enum SizeError {
+    #[fail(display = "event is {} bytes, which is over the limit", _0)]
+    EventTooLarge(usize),
+    #[fail(display = "{} is {} bytes, which is over the limit", _0, _1)]
+    FieldTooLarge(&'static str, usize),
+}
+
+/// Checks the event against all the size limits, returning every limit it
+/// exceeds.
+pub fn check_event_size(event: &Event, room_version: &RoomVersion) -> Vec<SizeError> {
+    let mut errors = Vec::new();
+
+    let mut fields = vec![
+        ("sender", &event.sender as &str),
+        ("room_id", &event.room_id),
+        ("type", &event.etype),
+    ];
+
+    if let Some(ref state_key) = event.state_key {
+        fields.push(("state_key", state_key));
+    }
+
+    // Event IDs in v3+ rooms are hashes, so are always short enough.
+    if room_version.event_format == EventFormat::V1 {
+        fields.push(("event_id", &event.event_id));
+    }
+
+    for (name, value) in fields {
+        if value.len() > MAX_FIELD_SIZE {
+            errors.push(SizeError::FieldTooLarge(name, value.len()));
+        }
+    }
+
+    // Compact JSON from serde_json has the same length as canonical JSON, and
+    // unlike it doesn't fail on events with floats.
+    let size = serde_json::to_string(&event.to_json(room_version))
+        .expect("event is valid JSON")
+        .len();
+    if size > MAX_EVENT_SIZE {
+        errors.push(SizeError::EventTooLarge(size));
+    }
+
+    errors
+}
+
+#[test]
+fn test_check_event_size() {
+    let mut event: Event = serde_json::from_str(
+        r#"{"sender": "@a:a", "room_id": "!r:a", "event_id": "$1:a", "type": "m.room.message",
+        "prev_events": [], "content": {}, "depth": 1}"#,
+    )
+    .unwrap();
+
+    assert_eq!(check_event_size(&event, &RoomVersion::V1), vec![]);
+
+    event.state_key = Some("a".repeat(256));
+    event
+        .content
+        .insert("body".into(), "a".repeat(MAX_EVENT_SIZE).into());
+
+    let errors = check_event_size(&event, &RoomVersion::V1);
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0], SizeError::FieldTooLarge("state_key", 256));
+}
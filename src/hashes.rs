@@ -0,0 +1,108 @@
+//! Event content and reference hashes.
+
+use base64;
+use failure::Error;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use auth::Event;
+use canonical_json::encode_canonical_json;
+use redaction::redact_json;
+use room_version::{EventFormat, RoomVersion};
+
+/// Computes the sha256 content hash of the JSON form of an event, i.e. the
+/// hash of its canonical JSON without `unsigned`, `signatures` or `hashes`.
+pub fn compute_content_hash(json: &Map<String, Value>) -> Result<Vec<u8>, Error> {
+    let mut json = json.clone();
+    json.remove("unsigned");
+    json.remove("signatures");
+    json.remove("hashes");
+
+    let encoded = encode_canonical_json(&Value::Object(json))?;
+
+    Ok(Sha256::digest(encoded.as_bytes()).to_vec())
+}
+
+/// Computes the reference hash of the JSON form of an event, i.e. the hash of
+/// the canonical JSON of the redacted event without `unsigned` or
+/// `signatures`.
+pub fn compute_reference_hash(
+    json: &Map<String, Value>,
+    room_version: &RoomVersion,
+) -> Result<Vec<u8>, Error> {
+    let mut redacted = redact_json(json, room_version);
+    redacted.remove("unsigned");
+    redacted.remove("signatures");
+
+    let encoded = encode_canonical_json(&Value::Object(redacted))?;
+
+    Ok(Sha256::digest(encoded.as_bytes()).to_vec())
+}
+
+/// Computes the ID of an event in a v3+ room from its reference hash.
+pub fn compute_event_id(event: &Event, room_version: &RoomVersion) -> Result<String, Error> {
+    let config = match room_version.event_format {
+        EventFormat::V1 => bail!("room version {} has explicit event IDs", room_version),
+        EventFormat::V2 => base64::STANDARD_NO_PAD,
+        EventFormat::V3 => base64::URL_SAFE_NO_PAD,
+    };
+
+    let json = event.to_json(room_version);
+    let hash = compute_reference_hash(json.as_object().expect("event is an object"), room_version)?;
+
+    Ok(format!("${}", base64::encode_config(&hash, config)))
+}
+
+/// Checks that the event's `hashes.sha256` matches its content.
+pub fn check_content_hash(event: &Event, room_version: &RoomVersion) -> Result<(), Error> {
+    let expected = event
+        .other
+        .get("hashes")
+        .and_then(|h| h.get("sha256"))
+        .and_then(Value::as_str)
+        .ok_or_else(|| format_err!("missing sha256 content hash"))?;
+
+    let json = event.to_json(room_version);
+    let hash = compute_content_hash(json.as_object().expect("event is an object"))?;
+    let actual = base64::encode_config(&hash, base64::STANDARD_NO_PAD);
+
+    ensure!(
+        actual == expected.trim_end_matches('='),
+        "content hash mismatch: expected {}, got {}",
+        expected,
+        actual
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_check_content_hash() {
+    use serde_json;
+
+    let mut event: Event = serde_json::from_str(
+        r#"{"sender": "@a:a", "room_id": "!r:a", "event_id": "$1:a", "type": "m.room.message",
+        "prev_events": [], "auth_events": [], "content": {"body": "hi"}, "depth": 2,
+        "origin_server_ts": 5}"#,
+    )
+    .unwrap();
+
+    assert!(check_content_hash(&event, &RoomVersion::V1).is_err());
+
+    let json = event.to_json(&RoomVersion::V1);
+    let hash = compute_content_hash(json.as_object().unwrap()).unwrap();
+    let hashes = format!(
+        r#"{{"sha256": "{}"}}"#,
+        base64::encode_config(&hash, base64::STANDARD_NO_PAD)
+    );
+    event
+        .other
+        .insert("hashes".into(), serde_json::from_str(&hashes).unwrap());
+
+    check_content_hash(&event, &RoomVersion::V1).unwrap();
+
+    event
+        .content
+        .insert("body".into(), Value::String("bye".into()));
+    assert!(check_content_hash(&event, &RoomVersion::V1).is_err());
+}
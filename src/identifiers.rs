@@ -0,0 +1,332 @@
+//! Matrix identifiers, parsed as per the grammar in the spec.
+
+use std::fmt;
+use std::net::Ipv6Addr;
+
+use auth::Event;
+use room_version::{EventFormat, RoomVersion};
+
+/// Why an identifier failed to parse. Each variant holds the identifier.
+#[derive(Debug, Clone, PartialEq, Eq, Fail)]
+pub enum IdError {
+    #[fail(display = "{} does not start with the expected sigil", _0)]
+    InvalidSigil(String),
+    #[fail(display = "{} has no server name", _0)]
+    MissingServerName(String),
+    #[fail(display = "{} has an invalid server name", _0)]
+    InvalidServerName(String),
+    #[fail(display = "{} has an invalid localpart", _0)]
+    InvalidLocalpart(String),
+    #[fail(display = "{} is not a valid reference hash", _0)]
+    InvalidHash(String),
+}
+
+/// A server name, i.e. a hostname with an optional port.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ServerName(String);
+
+impl ServerName {
+    pub fn parse(server_name: &str) -> Result<ServerName, IdError> {
+        if split_host_port(server_name).is_some() {
+            Ok(ServerName(server_name.to_string()))
+        } else {
+            Err(IdError::InvalidServerName(server_name.to_string()))
+        }
+    }
+
+    /// The hostname, which for IPv6 literals includes the brackets.
+    pub fn host(&self) -> &str {
+        split_host_port(&self.0).expect("server name is valid").0
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        split_host_port(&self.0).expect("server name is valid").1
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for ServerName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Splits a server name into its hostname and port, returning `None` if
+/// either is invalid.
+fn split_host_port(server_name: &str) -> Option<(&str, Option<u16>)> {
+    let (host, port) = if server_name.starts_with('[') {
+        let end = server_name.find(']')?;
+        server_name[1..end].parse::<Ipv6Addr>().ok()?;
+        server_name.split_at(end + 1)
+    } else {
+        let end = server_name.find(':').unwrap_or(server_name.len());
+        let host = &server_name[..end];
+
+        let valid = !host.is_empty()
+            && host.len() <= 255
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+        if !valid {
+            return None;
+        }
+
+        server_name.split_at(end)
+    };
+
+    if port.is_empty() {
+        return Some((host, None));
+    }
+
+    let digits = port.strip_prefix(':')?;
+    if digits.is_empty() || digits.len() > 5 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    Some((host, Some(digits.parse().ok()?)))
+}
+
+/// Splits an ID of the form `<sigil><localpart>:<server name>`.
+fn split_id(id: &str, sigil: char) -> Result<(&str, ServerName), IdError> {
+    let rest = id
+        .strip_prefix(sigil)
+        .ok_or_else(|| IdError::InvalidSigil(id.to_string()))?;
+
+    let colon = rest
+        .find(':')
+        .ok_or_else(|| IdError::MissingServerName(id.to_string()))?;
+
+    let server_name = ServerName::parse(&rest[colon + 1..])
+        .map_err(|_| IdError::InvalidServerName(id.to_string()))?;
+
+    Ok((&rest[..colon], server_name))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UserId {
+    localpart: String,
+    server_name: ServerName,
+}
+
+impl UserId {
+    /// Parses the user ID, accepting the historical grammar that allows any
+    /// printable ASCII in the localpart.
+    pub fn parse(id: &str) -> Result<UserId, IdError> {
+        let (localpart, server_name) = split_id(id, '@')?;
+
+        let valid = !localpart.is_empty()
+            && localpart
+                .chars()
+                .all(|c| c.is_ascii_graphic() && c != ':');
+        if !valid {
+            return Err(IdError::InvalidLocalpart(id.to_string()));
+        }
+
+        Ok(UserId {
+            localpart: localpart.to_string(),
+            server_name,
+        })
+    }
+
+    pub fn localpart(&self) -> &str {
+        &self.localpart
+    }
+
+    pub fn server_name(&self) -> &ServerName {
+        &self.server_name
+    }
+
+    /// Whether the localpart only conforms to the historical grammar.
+    pub fn is_historical(&self) -> bool {
+        !self.localpart.chars().all(|c| {
+            c.is_ascii_lowercase() || c.is_ascii_digit() || "._=-/+".contains(c)
+        })
+    }
+}
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "@{}:{}", self.localpart, self.server_name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RoomId {
+    localpart: String,
+    server_name: ServerName,
+}
+
+impl RoomId {
+    pub fn parse(id: &str) -> Result<RoomId, IdError> {
+        let (localpart, server_name) = split_id(id, '!')?;
+
+        if localpart.is_empty() {
+            return Err(IdError::InvalidLocalpart(id.to_string()));
+        }
+
+        Ok(RoomId {
+            localpart: localpart.to_string(),
+            server_name,
+        })
+    }
+
+    pub fn server_name(&self) -> &ServerName {
+        &self.server_name
+    }
+}
+
+impl fmt::Display for RoomId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "!{}:{}", self.localpart, self.server_name)
+    }
+}
+
+/// An event ID, which is either `$<localpart>:<server name>` in v1 and v2
+/// rooms, or `$` followed by the event's reference hash in v3+ rooms.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EventId {
+    id: String,
+    server_name: Option<ServerName>,
+}
+
+impl EventId {
+    pub fn parse(id: &str, room_version: &RoomVersion) -> Result<EventId, IdError> {
+        if room_version.event_format == EventFormat::V1 {
+            let (localpart, server_name) = split_id(id, '$')?;
+
+            if localpart.is_empty() {
+                return Err(IdError::InvalidLocalpart(id.to_string()));
+            }
+
+            return Ok(EventId {
+                id: id.to_string(),
+                server_name: Some(server_name),
+            });
+        }
+
+        let hash = id
+            .strip_prefix('$')
+            .ok_or_else(|| IdError::InvalidSigil(id.to_string()))?;
+
+        // The hash is unpadded base64 of a SHA-256 hash, URL safe from v4.
+        let valid = hash.len() == 43
+            && hash
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+/-_".contains(c));
+        if !valid {
+            return Err(IdError::InvalidHash(id.to_string()));
+        }
+
+        Ok(EventId {
+            id: id.to_string(),
+            server_name: None,
+        })
+    }
+
+    /// The server name, for events in v1 and v2 rooms.
+    pub fn server_name(&self) -> Option<&ServerName> {
+        self.server_name.as_ref()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.id
+    }
+}
+
+impl fmt::Display for EventId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.id)
+    }
+}
+
+/// Checks all the identifiers in the event, returning those that are
+/// malformed.
+pub fn find_malformed_ids(event: &Event, room_version: &RoomVersion) -> Vec<IdError> {
+    let mut errors = Vec::new();
+
+    if let Err(e) = UserId::parse(&event.sender) {
+        errors.push(e);
+    }
+
+    if let Err(e) = RoomId::parse(&event.room_id) {
+        errors.push(e);
+    }
+
+    let event_ids = Some(&event.event_id as &str)
+        .into_iter()
+        .chain(event.prev_events.iter().map(|e| e.event_id()))
+        .chain(event.auth_events().iter().map(|e| e.event_id()))
+        .chain(event.redacts());
+    for event_id in event_ids {
+        if let Err(e) = EventId::parse(event_id, room_version) {
+            errors.push(e);
+        }
+    }
+
+    if event.etype == "m.room.member" {
+        if let Some(ref state_key) = event.state_key {
+            if let Err(e) = UserId::parse(state_key) {
+                errors.push(e);
+            }
+        }
+    }
+
+    errors
+}
+
+#[test]
+fn test_server_name() {
+    let server_name = ServerName::parse("matrix.org:8448").unwrap();
+    assert_eq!(server_name.host(), "matrix.org");
+    assert_eq!(server_name.port(), Some(8448));
+
+    let server_name = ServerName::parse("[1234:5678::abcd]:80").unwrap();
+    assert_eq!(server_name.host(), "[1234:5678::abcd]");
+    assert_eq!(server_name.port(), Some(80));
+
+    assert!(ServerName::parse("1.2.3.4").is_ok());
+    assert!(ServerName::parse("[::1]").is_ok());
+
+    assert!(ServerName::parse("").is_err());
+    assert!(ServerName::parse("matrix.org:").is_err());
+    assert!(ServerName::parse("matrix.org:123456").is_err());
+    assert!(ServerName::parse("matrix.org:80:80").is_err());
+    assert!(ServerName::parse("matrix_org").is_err());
+    assert!(ServerName::parse("[::g]").is_err());
+    assert!(ServerName::parse("::1").is_err());
+}
+
+#[test]
+fn test_ids() {
+    let user_id = UserId::parse("@alice:[::1]:8448").unwrap();
+    assert_eq!(user_id.localpart(), "alice");
+    assert_eq!(user_id.server_name().host(), "[::1]");
+    assert!(!user_id.is_historical());
+    assert_eq!(user_id.to_string(), "@alice:[::1]:8448");
+
+    assert!(UserId::parse("@Alice!:a").unwrap().is_historical());
+    assert_eq!(
+        UserId::parse("@al ice:a"),
+        Err(IdError::InvalidLocalpart("@al ice:a".into()))
+    );
+    assert_eq!(
+        UserId::parse("alice:a"),
+        Err(IdError::InvalidSigil("alice:a".into()))
+    );
+    assert_eq!(
+        UserId::parse("@alice"),
+        Err(IdError::MissingServerName("@alice".into()))
+    );
+
+    assert_eq!(RoomId::parse("!r:a:80").unwrap().server_name().as_str(), "a:80");
+
+    let event_id = EventId::parse("$abc:a", &RoomVersion::V1).unwrap();
+    assert_eq!(event_id.server_name().unwrap().as_str(), "a");
+
+    let hash = "$acR1l0raoZnm60CBwAVgqbZqoO/mYU81xysh1u7XcJk";
+    assert_eq!(EventId::parse(hash, &RoomVersion::V3).unwrap().server_name(), None);
+    assert!(EventId::parse("$abc:a", &RoomVersion::V4).is_err());
+}
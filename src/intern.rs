@@ -0,0 +1,151 @@
+//! Interning of event IDs, event types and state keys.
+//!
+//! Large rooms have hundreds of thousands of state groups that mostly
+//! reference the same events and users, so rather than storing the strings
+//! everywhere we store small handles to a single copy of each.
+//!
+//! The interned strings live for the rest of the program.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{OnceLock, RwLock};
+
+use heapsize::HeapSizeOf;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Default)]
+struct Interner {
+    handles: HashMap<&'static str, u32>,
+    strings: Vec<&'static str>,
+}
+
+impl Interner {
+    fn intern(lock: &RwLock<Interner>, s: &str) -> u32 {
+        if let Some(handle) = Interner::get(lock, s) {
+            return handle;
+        }
+
+        let mut interner = lock.write().expect("interner lock poisoned");
+        if let Some(&handle) = interner.handles.get(s) {
+            return handle;
+        }
+
+        let s: &'static str = Box::leak(s.to_string().into_boxed_str());
+        let handle = interner.strings.len() as u32;
+        interner.strings.push(s);
+        interner.handles.insert(s, handle);
+
+        handle
+    }
+
+    fn get(lock: &RwLock<Interner>, s: &str) -> Option<u32> {
+        lock.read()
+            .expect("interner lock poisoned")
+            .handles
+            .get(s)
+            .cloned()
+    }
+
+    fn resolve(lock: &RwLock<Interner>, handle: u32) -> &'static str {
+        lock.read().expect("interner lock poisoned").strings[handle as usize]
+    }
+}
+
+macro_rules! handle_type {
+    ($(#[$meta:meta])* $name:ident, $interner:ident) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub struct $name(u32);
+
+        fn $interner() -> &'static RwLock<Interner> {
+            static INTERNER: OnceLock<RwLock<Interner>> = OnceLock::new();
+            INTERNER.get_or_init(Default::default)
+        }
+
+        impl $name {
+            /// Get the handle for the string, interning it if necessary.
+            pub fn intern(s: &str) -> $name {
+                $name(Interner::intern($interner(), s))
+            }
+
+            /// Get the handle for the string if it has been interned.
+            pub fn get(s: &str) -> Option<$name> {
+                Interner::get($interner(), s).map($name)
+            }
+
+            pub fn as_str(self) -> &'static str {
+                Interner::resolve($interner(), self.0)
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{:?}", self.as_str())
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(self.as_str())
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<$name, D::Error> {
+                String::deserialize(deserializer).map(|s| $name::intern(&s))
+            }
+        }
+
+        // The strings are shared, so aren't counted against any one handle.
+        impl HeapSizeOf for $name {
+            fn heap_size_of_children(&self) -> usize {
+                0
+            }
+        }
+    };
+}
+
+handle_type!(
+    /// An interned event ID.
+    ///
+    /// Handles are ordered by when they were interned, not by the ID.
+    EventHandle,
+    event_ids
+);
+
+handle_type!(
+    /// An interned state key.
+    StateKeyHandle,
+    state_keys
+);
+
+handle_type!(
+    /// An interned event type.
+    EventTypeHandle,
+    event_types
+);
+
+#[test]
+fn test_intern() {
+    let handle = EventHandle::intern("$test_intern:a");
+    assert_eq!(EventHandle::intern("$test_intern:a"), handle);
+    assert_eq!(EventHandle::get("$test_intern:a"), Some(handle));
+    assert_eq!(handle.as_str(), "$test_intern:a");
+    assert_eq!(handle.to_string(), "$test_intern:a");
+
+    let json = ::serde_json::to_string(&handle).unwrap();
+    assert_eq!(json, r#""$test_intern:a""#);
+    assert_eq!(::serde_json::from_str::<EventHandle>(&json).unwrap(), handle);
+
+    assert_eq!(EventHandle::get("$test_intern_missing:a"), None);
+
+    // Event IDs, event types and state keys are interned separately.
+    assert_eq!(StateKeyHandle::get("$test_intern:a"), None);
+    assert_eq!(EventTypeHandle::get("$test_intern:a"), None);
+}
@@ -0,0 +1,44 @@
+// serde_derive 1.0.37 puts the impls it generates inside named consts, which
+// newer compilers warn about.
+#![allow(non_local_definitions)]
+
+#[macro_use]
+extern crate serde_derive;
+extern crate base64;
+extern crate ed25519_dalek;
+extern crate heapsize;
+extern crate postgres;
+extern crate serde;
+extern crate serde_json;
+extern crate sha1;
+extern crate sha2;
+#[macro_use]
+extern crate failure;
+#[macro_use]
+extern crate failure_derive;
+extern crate smallvec;
+
+pub mod auth;
+pub mod auth_chain;
+pub mod canonical_json;
+pub mod content;
+pub mod db;
+pub mod event_size;
+pub mod hashes;
+pub mod identifiers;
+pub mod intern;
+pub mod persistent_state_map;
+pub mod power_levels;
+pub mod redaction;
+pub mod room;
+pub mod room_version;
+pub mod server_acl;
+pub mod signatures;
+pub mod soft_fail;
+pub mod state;
+pub mod state_group_store;
+pub mod state_map;
+
+pub use intern::EventHandle;
+pub use room::{RoomDag, StateCalculator, StateGroups};
+pub use room_version::RoomVersion;
@@ -1,25 +1,14 @@
-#[macro_use]
-extern crate serde_derive;
 extern crate heapsize;
 extern crate indicatif;
 extern crate postgres;
 extern crate procinfo;
-extern crate serde;
-extern crate serde_json;
-extern crate sha1;
-#[macro_use]
-extern crate failure;
-#[macro_use]
-extern crate heapsize_derive;
-extern crate smallvec;
+extern crate rust_state;
 #[macro_use]
 extern crate clap;
 
-use std::borrow::Cow;
-use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::BufReader;
 use std::mem;
 use std::time::Instant;
 
@@ -27,11 +16,16 @@ use clap::{App, Arg};
 use heapsize::HeapSizeOf;
 use indicatif::ProgressBar;
 
-pub mod auth;
-pub mod state;
-pub mod state_map;
-
-use state_map::StateMap;
+use rust_state::auth;
+use rust_state::auth_chain;
+use rust_state::db;
+use rust_state::event_size;
+use rust_state::hashes;
+use rust_state::identifiers;
+use rust_state::power_levels::{IntegerParsing, PowerLevels};
+use rust_state::signatures;
+use rust_state::soft_fail;
+use rust_state::{EventHandle, RoomDag, RoomVersion, StateCalculator, StateGroups};
 
 fn main() {
     let matches = App::new(crate_name!())
@@ -45,6 +39,44 @@ fn main() {
             .help("Postgres connection string")
             .index(2)
             .required(false))
+        .arg(Arg::with_name("auth-chain")
+            .help("Print the auth chain of the given event and exit")
+            .long("auth-chain")
+            .value_name("EVENT_ID")
+            .takes_value(true))
+        .arg(Arg::with_name("verify-signatures")
+            .help("Check event signatures against the server keys in the given file or directory and exit")
+            .long("verify-signatures")
+            .value_name("KEY_STORE")
+            .takes_value(true))
+        .arg(Arg::with_name("auth-trace")
+            .help("Print how the auth rules decided on the given event and exit")
+            .long("auth-trace")
+            .value_name("EVENT_ID")
+            .takes_value(true))
+        .arg(Arg::with_name("soft-failures")
+            .help("Report events that would have been soft failed")
+            .long("soft-failures"))
+        .arg(Arg::with_name("verify-hashes")
+            .help("Check the content hashes of all events and exit")
+            .long("verify-hashes"))
+        .arg(Arg::with_name("check-sizes")
+            .help("Report events that exceed the event or field size limits and exit")
+            .long("check-sizes"))
+        .arg(Arg::with_name("reject-oversized")
+            .help("Treat events that exceed the size limits as rejected when calculating state")
+            .long("reject-oversized"))
+        .arg(Arg::with_name("check-ids")
+            .help("Report events with malformed identifiers and exit")
+            .long("check-ids"))
+        .arg(Arg::with_name("strict-power-levels")
+            .help("Report power level events that would be rejected in rooms requiring integer power levels and exit")
+            .long("strict-power-levels"))
+        .arg(Arg::with_name("max-delta-chain")
+            .help("The maximum number of deltas per snapshot when delta encoding state groups")
+            .long("max-delta-chain")
+            .value_name("LENGTH")
+            .takes_value(true))
         .get_matches();
 
     let file_path = value_t_or_exit!(matches, "input", String);
@@ -53,36 +85,10 @@ fn main() {
     let f = File::open(file_path).unwrap();
     let f = BufReader::new(f);
 
-    let mut event_map = HashMap::new(); // event_id -> event
-    let mut parents = HashMap::new();  // event_id -> list of parent event_ids
-    let mut extremities = HashSet::new();  // set of forward extremities
-    let mut roots = Vec::new(); // set of events that have no children
-
     let start = Instant::now();
 
     // Read in the events and add them to event_map and co.
-    for line in f.lines() {
-        let line = line.unwrap();
-        let event: auth::Event = serde_json::from_str(&line).unwrap();
-
-        for eid in event.prev_events.iter().map(|v| v.0.clone()) {
-            extremities.remove(&eid);
-            parents
-                .entry(eid)
-                .or_insert_with(HashSet::new)
-                .insert(event.event_id.clone());
-        }
-
-        if !parents.contains_key(&event.event_id) {
-            extremities.insert(event.event_id.clone());
-        }
-
-        if event.prev_events.is_empty() {
-            roots.push(event.event_id.clone());
-        }
-
-        event_map.insert(event.event_id.clone(), event);
-    }
+    let mut dag = RoomDag::from_reader(f).unwrap();
 
     println!(
         "Reading took {}",
@@ -91,27 +97,69 @@ fn main() {
 
     println!("Missing:");
 
-    for (r, _) in &parents {
-        if !event_map.contains_key(r) {
-            println!("\t{}", r);
-        }
+    for r in dag.missing() {
+        println!("\t{}", r);
     }
 
     println!("Extremities:");
 
-    for e in &extremities {
+    for e in &dag.extremities {
         println!("\t{}", e);
     }
 
     println!("Roots:");
-    for e in &roots {
+    for e in &dag.roots {
         println!("\t{}", e);
     }
 
+    if let Some(event_id) = matches.value_of("auth-chain") {
+        print_auth_chain(event_id, &dag.event_map);
+        return;
+    }
+
+    let room_version = dag.room_version().unwrap();
+    println!("Room version: {}", room_version);
+
+    if matches.is_present("verify-hashes") {
+        print_hash_failures(&dag.event_map, &room_version);
+        return;
+    }
+
+    if matches.is_present("check-sizes") {
+        print_size_failures(&dag.event_map, &room_version);
+        return;
+    }
+
+    if matches.is_present("check-ids") {
+        print_malformed_ids(&dag.event_map, &room_version);
+        return;
+    }
+
+    if matches.is_present("strict-power-levels") {
+        print_strict_power_level_failures(&dag.event_map);
+        return;
+    }
+
+    if let Some(key_store_path) = matches.value_of("verify-signatures") {
+        let key_store = signatures::KeyStore::load(key_store_path).unwrap();
+        print_signature_failures(&dag.event_map, &room_version, &key_store);
+        return;
+    }
+
+    // Servers auth events against the redacted form of redacted state, so we
+    // do the same.
+    let redacted = dag.apply_redactions(&room_version);
+    println!("Redacted state events: {}", redacted.len());
+
+    if let Some(event_id) = matches.value_of("auth-trace") {
+        print_auth_trace(event_id, &dag.event_map, &room_version);
+        return;
+    }
+
     let start = Instant::now();
 
     // Get a list of events in topological order
-    let ordered = get_ordered_fast(&event_map, &extremities, &parents);
+    let ordered = dag.get_ordered();
 
     println!(
         "Ordering took {}",
@@ -125,78 +173,17 @@ fn main() {
 
     let pb = ProgressBar::new(ordered.len() as u64);
 
-    let mut next_sg = 0;
-
-    // Multiple events may share the same state, so lets give the state an ID
-    // called "state group" and have two maps for event_id -> sg -> state
-    let mut event_to_sg = HashMap::new();
-    let mut sg_to_state = HashMap::new();
-
     let start = Instant::now();
 
+    let mut calculator = StateCalculator::new(&dag, room_version);
+    calculator.set_reject_oversized(matches.is_present("reject-oversized"));
+    if matches.is_present("max-delta-chain") {
+        calculator.set_max_delta_chain(value_t_or_exit!(matches, "max-delta-chain", usize));
+    }
+
     let mut i = 0;
     for eid in &ordered {
-        let event = &event_map[eid];
-
-        // The block returns the new state group if a new one was created.
-        let state = {
-            // Whether the state is the same as a previous state group.
-            let mut current_sg = None;
-
-            // Work out the resolved state for all prev_events
-            let mut state: Cow<StateMap<_>> = if event.prev_events.len() > 1 {
-                let state_sets = event
-                    .prev_events
-                    .iter()
-                    .map(|v| &v.0)
-                    .filter_map(|pid| {
-                        if let Some(sg) = event_to_sg.get(pid) {
-                            if let Some(state) = sg_to_state.get(sg) {
-                                Some(state)
-                            } else {
-                                panic!("Failed to find state for event: {}, {}", pid, eid);
-                            }
-                        } else {
-                            // panic!("Failed to find sg for event: {}, processing: {}", pid, eid);
-                            // println!("Ignoring event: {}", pid);
-                            None
-                        }
-                    })
-                    .collect();
-
-                Cow::Owned(state::resolve_state(state_sets, &event_map))
-            } else if event.prev_events.len() == 1 {
-                let s = event_to_sg[&event.prev_events[0].0];
-                current_sg = Some(s);
-                Cow::Borrowed(&sg_to_state[&s])
-            } else {
-                Cow::Owned(StateMap::new())
-            };
-
-            // If this is a state event then we add it to the state
-            if let Some(ref state_key) = event.state_key {
-                current_sg = None;
-                state.to_mut().insert(&event.etype, &state_key, eid.clone());
-            }
-
-            // If nothing has changed we reuse the state group, otherwise
-            // create a new one.
-            if let Some(sg) = current_sg {
-                event_to_sg.insert(eid.clone(), sg);
-                None
-            } else {
-                let sg = next_sg + 1;
-                next_sg += 1;
-
-                event_to_sg.insert(eid.clone(), sg);
-                Some((sg, state.into_owned()))
-            }
-        };
-
-        // If we generated a new state group, persist it.
-        if let Some((sg, state)) = state {
-            sg_to_state.insert(sg, state);
-        }
+        calculator.process_event(*eid);
 
         // Increment progress bar occaisonally (doing it on each loop is slow)
         i += 1;
@@ -205,6 +192,8 @@ fn main() {
         }
     }
 
+    let groups = calculator.into_groups();
+
     pb.finish();
 
     println!(
@@ -212,20 +201,27 @@ fn main() {
         indicatif::HumanDuration(Instant::now() - start)
     );
 
-    println!("{}", sg_to_state.len());
+    println!("{}", groups.store.len());
 
     println!(
         "Size: {}",
-        indicatif::HumanBytes(event_to_sg.heap_size_of_children() as u64)
+        indicatif::HumanBytes(groups.event_to_sg.heap_size_of_children() as u64)
     );
+
     println!(
-        "Size: {}",
-        indicatif::HumanBytes(sg_to_state.heap_size_of_children() as u64)
+        "Delta encoded size: {}",
+        indicatif::HumanBytes(groups.store.heap_size_of_children() as u64)
     );
 
     let statm = procinfo::pid::statm_self().unwrap();
     println!("{}", indicatif::HumanBytes(statm.resident as u64 * 4096));
 
+    print_rejected_events(&dag, &groups, &ordered);
+
+    if matches.is_present("soft-failures") {
+        print_soft_failures(&dag, &groups, &ordered, &room_version);
+    }
+
     // If we have a db connection, lets see what the difference is between what we
     // think the state is and what the db thinks it is.
     if let Some(pg_conn_str) = pg_conn_str {
@@ -235,148 +231,263 @@ fn main() {
         ).unwrap();
 
         // First, lets do a binary search for the first place our views diverge
-        let res = ordered.binary_search_by(|event_id| {
-            let state: HashSet<_> = sg_to_state[&event_to_sg[event_id]]
-                .values()
-                .cloned()
-                .collect();
-
-            let actual = get_state(&conn, event_id);
-
-            if state == actual {
-                Ordering::Less
-            } else {
-                Ordering::Greater
-            }
-        });
-
-        let i = res.unwrap_err();
-
-        if i < ordered.len() {
+        if let Some(i) = db::find_first_divergence(&conn, &ordered, &groups) {
             println!("\nFirst divergence: {} at {}", &ordered[i], i);
 
-            print_difference(
-                &ordered[i],
-                &conn,
-                &event_to_sg,
-                &sg_to_state,
-                &event_map,
-            );
+            print_difference(ordered[i], &conn, &groups);
         }
 
         // Now output the difference for each extremity.
-        for e in &extremities {
+        for e in &dag.extremities {
             println!("\nDifference at extremity {}", e);
 
-            print_difference(e, &conn, &event_to_sg, &sg_to_state, &event_map);
+            print_difference(*e, &conn, &groups);
         }
     }
 
     // Leak these large objects, as their deallocation take a bit of time and
     // we're about to exit...
-    mem::forget(sg_to_state);
-    mem::forget(event_map);
-    mem::forget(event_to_sg);
+    mem::forget(groups);
+    mem::forget(dag);
     mem::forget(ordered);
-    mem::forget(parents);
 }
 
-fn get_state(conn: &postgres::Connection, event_id: &str) -> HashSet<String> {
-    let q = conn.query(GET_STATE_QUERY, &[&event_id]).unwrap();
+fn print_difference(event_id: EventHandle, conn: &postgres::Connection, groups: &StateGroups) {
+    let actual = db::get_state(conn, event_id.as_str());
+    let state = groups.get_state(event_id).expect("state was calculated").to_state_map();
 
-    q.iter().map(|row| row.get(0)).collect()
-}
+    // Entries are shown going from the database's state to ours.
+    let delta = actual.diff(&state);
 
-const GET_STATE_QUERY: &str = r#"
-    WITH RECURSIVE state(state_group) AS (
-        SELECT state_group FROM event_to_state_groups WHERE event_id = $1
-        UNION ALL
-        SELECT prev_state_group FROM state_group_edges e, state s
-        WHERE s.state_group = e.state_group
-    )
-    SELECT DISTINCT last_value(event_id) OVER (
-        PARTITION BY type, state_key ORDER BY state_group ASC
-        ROWS BETWEEN UNBOUNDED PRECEDING AND UNBOUNDED FOLLOWING
-    ) AS event_id FROM state_groups_state
-    WHERE state_group IN (
-        SELECT state_group FROM state
-    )
-"#;
-
-/// Return list of events in topological ordering, with root first.
-fn get_ordered_fast(
-    event_map: &HashMap<String, auth::Event>,
-    extremities: &HashSet<String>,
-    parents: &HashMap<String, HashSet<String>>,
-) -> Vec<String> {
-    let mut ordered = Vec::with_capacity(event_map.len());
-
-    let mut zeroes: Vec<_> = extremities.iter().collect();
-    let mut adjacents: HashMap<&str, usize> = event_map
-        .keys()
-        .map(|key| (key as &str, parents.get(key).map(HashSet::len).unwrap_or(0)))
-        .collect();
+    for &((ref t, ref s), ref e) in &delta.removed {
+        println!(" - ({}, {}) {}", t, s, e);
+    }
 
-    while let Some(event_id) = zeroes.pop() {
-        ordered.push(event_id.clone());
-
-        for (p, _) in &event_map[event_id].prev_events {
-            let a = if let Some(mut i) = adjacents.get_mut(p as &str) {
-                *i -= 1;
-                *i
-            } else {
-                1
-            };
-
-            if a == 0 {
-                adjacents.remove(p as &str);
-                zeroes.push(p);
-            }
-        }
+    for &((ref t, ref s), ref e) in &delta.added {
+        println!(" + ({}, {}) {}", t, s, e);
     }
 
-    assert_eq!(ordered.len(), event_map.len());
+    for &((ref t, ref s), ref old, ref new) in &delta.changed {
+        println!(" ~ ({}, {}) {} -> {}", t, s, old, new);
+    }
 
-    ordered.reverse();
-    ordered
+    if delta.is_empty() {
+        println!(" No difference");
+    }
 }
 
-fn print_difference(
-    event_id: &str,
-    conn: &postgres::Connection,
-    event_to_state: &HashMap<String, i32>,
-    sg_to_state: &HashMap<i32, StateMap<String>>,
-    event_map: &HashMap<String, auth::Event>,
-) {
-    let actual = get_state(&conn, event_id);
-    let state: HashSet<_> = sg_to_state[&event_to_state[event_id]]
-        .values()
-        .cloned()
-        .collect();
+fn print_auth_chain(event_id: &str, event_map: &HashMap<EventHandle, auth::Event>) {
+    println!("\nAuth chain of {}", event_id);
+
+    let event_id = match EventHandle::get(event_id) {
+        Some(event_id) if event_map.contains_key(&event_id) => event_id,
+        _ => {
+            println!(" Unknown event");
+            return;
+        }
+    };
 
-    let mut difference = false;
-    for e in actual.symmetric_difference(&state) {
-        difference = true;
+    let mut chain: Vec<_> = auth_chain::get_auth_chain(vec![event_id], event_map)
+        .into_iter()
+        .collect();
+    chain.sort_by_key(|e| (event_map.get(e).map(|ev| ev.depth), e.as_str()));
 
-        let event = &event_map[e];
-        if actual.contains(e) {
+    for e in chain {
+        if let Some(event) = event_map.get(&e) {
             println!(
-                " - ({}, {}) {}",
+                " {} ({}, {}) {}",
+                event.depth,
                 event.etype,
-                event.state_key.as_ref().unwrap(),
+                event.state_key.as_ref().map(|s| s as &str).unwrap_or(""),
                 e
             );
         } else {
+            println!(" ? (missing) {}", e);
+        }
+    }
+}
+
+fn print_auth_trace(
+    event_id: &str,
+    event_map: &HashMap<EventHandle, auth::Event>,
+    room_version: &RoomVersion,
+) {
+    println!("\nAuth trace of {}", event_id);
+
+    let event = if let Some(event) = EventHandle::get(event_id).and_then(|e| event_map.get(&e)) {
+        event
+    } else {
+        println!(" Unknown event");
+        return;
+    };
+
+    let auth_events = auth_chain::get_auth_events(event, event_map);
+
+    let mut trace = auth::AuthTrace::new();
+    // The outcome is recorded in the trace.
+    let _ = auth::check_traced(event, &auth_events, room_version, &mut trace);
+
+    println!(" Auth events:");
+    for (etype, state_key, auth_event_id) in &trace.auth_events {
+        println!("\t({}, {}) {}", etype, state_key, auth_event_id);
+    }
+
+    println!(" Rules:");
+    for step in &trace.steps {
+        match *step {
+            auth::TraceStep::Rule(rule) => println!("\t{}", rule),
+            auth::TraceStep::PowerLevel {
+                ref name,
+                required,
+                actual,
+            } => println!(
+                "\t\tpower level {}: required {}, actual {}",
+                name, required, actual
+            ),
+        }
+    }
+
+    match trace.outcome {
+        Some(Ok(())) => println!(" Outcome: allowed"),
+        Some(Err(ref err)) => println!(" Outcome: rejected ({}): {}", err.code(), err),
+        None => {}
+    }
+}
+
+fn print_rejected_events(dag: &RoomDag, groups: &StateGroups, ordered: &[EventHandle]) {
+    println!("\nRejected events: {}/{}", groups.rejected.len(), ordered.len());
+
+    for eid in ordered {
+        if groups.rejected.contains(eid) {
+            let event = &dag.event_map[eid];
             println!(
-                " + ({}, {}) {}",
+                "\t{} {} ({}, {})",
+                event.depth,
+                eid,
                 event.etype,
-                event.state_key.as_ref().unwrap(),
-                e
+                event.state_key.as_ref().map(|s| s as &str).unwrap_or("")
             );
         }
     }
+}
 
-    if !difference {
-        println!(" No difference");
+fn print_soft_failures(
+    dag: &RoomDag,
+    groups: &StateGroups,
+    ordered: &[EventHandle],
+    room_version: &RoomVersion,
+) {
+    let failures = soft_fail::find_soft_failures(dag, groups, ordered, room_version);
+
+    println!("\nSoft failed events: {}/{}", failures.len(), ordered.len());
+
+    for failure in failures {
+        let event = &dag.event_map[&failure.event_id];
+        println!(
+            "\t{} {} ({}): {}",
+            event.depth,
+            failure.event_id,
+            failure.error.code(),
+            failure.error
+        );
+    }
+}
+
+fn print_signature_failures(
+    event_map: &HashMap<EventHandle, auth::Event>,
+    room_version: &RoomVersion,
+    key_store: &signatures::KeyStore,
+) {
+    let mut failures: Vec<_> = event_map
+        .values()
+        .filter_map(|event| {
+            signatures::verify_event(event, room_version, key_store)
+                .err()
+                .map(|e| (event, e))
+        })
+        .collect();
+    failures.sort_by_key(|&(event, _)| (event.depth, &event.event_id));
+
+    println!("\nSignature failures: {}/{}", failures.len(), event_map.len());
+
+    for (event, err) in failures {
+        println!("\t{} {}: {}", event.depth, event.event_id, err);
+    }
+}
+
+fn print_hash_failures(event_map: &HashMap<EventHandle, auth::Event>, room_version: &RoomVersion) {
+    let mut failures: Vec<_> = event_map
+        .values()
+        .filter_map(|event| {
+            hashes::check_content_hash(event, room_version)
+                .err()
+                .map(|e| (event, e))
+        })
+        .collect();
+    failures.sort_by_key(|&(event, _)| (event.depth, &event.event_id));
+
+    println!("\nContent hash failures: {}/{}", failures.len(), event_map.len());
+
+    for (event, err) in failures {
+        println!("\t{} {}: {}", event.depth, event.event_id, err);
+    }
+}
+
+fn print_strict_power_level_failures(event_map: &HashMap<EventHandle, auth::Event>) {
+    let mut power_levels: Vec<_> = event_map
+        .values()
+        .filter(|event| event.etype == "m.room.power_levels" && event.state_key.is_some())
+        .collect();
+    power_levels.sort_by_key(|event| (event.depth, &event.event_id));
+
+    let failures: Vec<_> = power_levels
+        .iter()
+        .filter(|event| {
+            PowerLevels::validate_content(&event.content, IntegerParsing::Strict).is_err()
+        })
+        .collect();
+
+    println!(
+        "\nPower level events with non-integer levels: {}/{}",
+        failures.len(),
+        power_levels.len()
+    );
+
+    for event in failures {
+        println!("\t{} {}", event.depth, event.event_id);
+    }
+}
+
+fn print_malformed_ids(event_map: &HashMap<EventHandle, auth::Event>, room_version: &RoomVersion) {
+    let mut failures: Vec<_> = event_map
+        .values()
+        .map(|event| (event, identifiers::find_malformed_ids(event, room_version)))
+        .filter(|(_, errors)| !errors.is_empty())
+        .collect();
+    failures.sort_by_key(|&(event, _)| (event.depth, &event.event_id));
+
+    println!("\nEvents with malformed IDs: {}/{}", failures.len(), event_map.len());
+
+    for (event, errors) in failures {
+        for err in errors {
+            println!("\t{} {}: {}", event.depth, event.event_id, err);
+        }
+    }
+}
+
+fn print_size_failures(event_map: &HashMap<EventHandle, auth::Event>, room_version: &RoomVersion) {
+    let mut failures: Vec<_> = event_map
+        .values()
+        .map(|event| (event, event_size::check_event_size(event, room_version)))
+        .filter(|(_, errors)| !errors.is_empty())
+        .collect();
+    failures.sort_by_key(|&(event, _)| (event.depth, &event.event_id));
+
+    println!("\nEvents over the size limits: {}/{}", failures.len(), event_map.len());
+
+    for (event, errors) in failures {
+        for err in errors {
+            println!("\t{} {}: {}", event.depth, event.event_id, err);
+        }
     }
 }
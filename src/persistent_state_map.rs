@@ -0,0 +1,521 @@
+//! A persistent variant of `StateMap`.
+//!
+//! The map is a hash array mapped trie, so cloning is O(1) and inserting is
+//! O(log n), with the new map sharing everything but the changed path with
+//! the old one. This makes it cheap to keep the state at every event, as
+//! memory grows with the number of changes rather than with the number of
+//! state groups times the size of the room state.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter::FromIterator;
+use std::mem;
+use std::slice;
+use std::sync::Arc;
+
+use heapsize::HeapSizeOf;
+
+use intern::{EventTypeHandle, StateKeyHandle};
+use state_map::{StateDelta, StateMap};
+
+/// The number of hash bits used to pick a slot at each level of the trie.
+const BITS: u32 = 5;
+const MASK: u64 = (1 << BITS) - 1;
+
+#[derive(Debug, Clone)]
+struct Entry<E> {
+    etype: EventTypeHandle,
+    state_key: StateKeyHandle,
+    value: E,
+}
+
+impl<E> Entry<E> {
+    fn is(&self, t: EventTypeHandle, s: StateKeyHandle) -> bool {
+        self.state_key == s && self.etype == t
+    }
+}
+
+/// The entries whose keys have the given hash.
+#[derive(Debug, Clone)]
+struct Leaf<E> {
+    hash: u64,
+    entries: Vec<Entry<E>>,
+}
+
+#[derive(Debug, Clone)]
+struct Branch<E> {
+    /// Which of the slots at this level are occupied, with `children` only
+    /// holding the occupied ones.
+    bitmap: u32,
+    children: Vec<Node<E>>,
+}
+
+#[derive(Debug, Clone)]
+enum Node<E> {
+    Leaf(Arc<Leaf<E>>),
+    Branch(Arc<Branch<E>>),
+}
+
+fn hash_key(t: EventTypeHandle, s: StateKeyHandle) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    t.hash(&mut hasher);
+    s.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn slot_bit(hash: u64, shift: u32) -> u32 {
+    1 << ((hash >> shift) & MASK)
+}
+
+impl<E: Clone> Branch<E> {
+    fn position(&self, bit: u32) -> usize {
+        (self.bitmap & (bit - 1)).count_ones() as usize
+    }
+
+    fn get(&self, hash: u64, shift: u32, t: EventTypeHandle, s: StateKeyHandle) -> Option<&E> {
+        let bit = slot_bit(hash, shift);
+        if self.bitmap & bit == 0 {
+            return None;
+        }
+
+        match self.children[self.position(bit)] {
+            Node::Leaf(ref leaf) if leaf.hash == hash => leaf.entries
+                .iter()
+                .find(|e| e.is(t, s))
+                .map(|e| &e.value),
+            Node::Leaf(_) => None,
+            Node::Branch(ref branch) => branch.get(hash, shift + BITS, t, s),
+        }
+    }
+
+    /// Inserts the entry, copying any nodes on the path that are shared.
+    fn insert(&mut self, hash: u64, shift: u32, entry: Entry<E>) -> Option<E> {
+        let bit = slot_bit(hash, shift);
+        let pos = self.position(bit);
+
+        if self.bitmap & bit == 0 {
+            self.bitmap |= bit;
+            self.children.insert(
+                pos,
+                Node::Leaf(Arc::new(Leaf {
+                    hash,
+                    entries: vec![entry],
+                })),
+            );
+            return None;
+        }
+
+        let child = &mut self.children[pos];
+        let new_child = match *child {
+            Node::Branch(ref mut branch) => {
+                return Arc::make_mut(branch).insert(hash, shift + BITS, entry);
+            }
+            Node::Leaf(ref mut leaf) if leaf.hash == hash => {
+                let leaf = Arc::make_mut(leaf);
+                if let Some(existing) = leaf.entries
+                    .iter_mut()
+                    .find(|e| e.is(entry.etype, entry.state_key))
+                {
+                    return Some(mem::replace(&mut existing.value, entry.value));
+                }
+
+                leaf.entries.push(entry);
+                return None;
+            }
+            Node::Leaf(ref leaf) => {
+                // The hashes differ, so push the existing leaf down a level
+                // and try again there.
+                let mut branch = Branch {
+                    bitmap: slot_bit(leaf.hash, shift + BITS),
+                    children: vec![Node::Leaf(leaf.clone())],
+                };
+                branch.insert(hash, shift + BITS, entry);
+                Node::Branch(Arc::new(branch))
+            }
+        };
+
+        *child = new_child;
+        None
+    }
+
+    /// Removes the entry, which must be in the map.
+    fn remove(&mut self, hash: u64, shift: u32, t: EventTypeHandle, s: StateKeyHandle) -> E {
+        let bit = slot_bit(hash, shift);
+        let pos = self.position(bit);
+
+        let (removed, now_empty) = match self.children[pos] {
+            Node::Leaf(ref mut leaf) => {
+                let leaf = Arc::make_mut(leaf);
+                let idx = leaf.entries
+                    .iter()
+                    .position(|e| e.is(t, s))
+                    .expect("entry in map");
+                let entry = leaf.entries.swap_remove(idx);
+                (entry.value, leaf.entries.is_empty())
+            }
+            Node::Branch(ref mut branch) => {
+                let branch = Arc::make_mut(branch);
+                let removed = branch.remove(hash, shift + BITS, t, s);
+                (removed, branch.children.is_empty())
+            }
+        };
+
+        if now_empty {
+            self.bitmap &= !bit;
+            self.children.remove(pos);
+        }
+
+        removed
+    }
+}
+
+/// A persistent map from `(type, state_key)` to `E`.
+#[derive(Clone)]
+pub struct PersistentStateMap<E> {
+    root: Arc<Branch<E>>,
+    len: usize,
+}
+
+impl<E: Clone> PersistentStateMap<E> {
+    pub fn new() -> PersistentStateMap<E> {
+        PersistentStateMap {
+            root: Arc::new(Branch {
+                bitmap: 0,
+                children: Vec::new(),
+            }),
+            len: 0,
+        }
+    }
+
+    pub fn from_state_map(state: &StateMap<E>) -> PersistentStateMap<E>
+    where
+        E: fmt::Debug,
+    {
+        state.iter().map(|(k, e)| (k, e.clone())).collect()
+    }
+
+    pub fn to_state_map(&self) -> StateMap<E>
+    where
+        E: fmt::Debug,
+    {
+        self.iter().map(|(k, e)| (k, e.clone())).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, t: &str, s: &str) -> Option<&E> {
+        let t = EventTypeHandle::get(t)?;
+        let s = StateKeyHandle::get(s)?;
+        self.root.get(hash_key(t, s), 0, t, s)
+    }
+
+    pub fn contains_key(&self, t: &str, s: &str) -> bool {
+        self.get(t, s).is_some()
+    }
+
+    /// Inserts the value, returning the previous one. Maps this was cloned
+    /// from (or into) are unaffected.
+    pub fn insert(&mut self, t: &str, s: &str, value: E) -> Option<E> {
+        let t = EventTypeHandle::intern(t);
+        let s = StateKeyHandle::intern(s);
+        let entry = Entry {
+            etype: t,
+            state_key: s,
+            value,
+        };
+
+        let prev = Arc::make_mut(&mut self.root).insert(hash_key(t, s), 0, entry);
+        if prev.is_none() {
+            self.len += 1;
+        }
+
+        prev
+    }
+
+    pub fn remove(&mut self, t: &str, s: &str) -> Option<E> {
+        // Check first so we don't copy the path for a missing key.
+        if !self.contains_key(t, s) {
+            return None;
+        }
+
+        let t = EventTypeHandle::get(t).expect("event type in map");
+        let s = StateKeyHandle::get(s).expect("state key in map");
+        let removed = Arc::make_mut(&mut self.root).remove(hash_key(t, s), 0, t, s);
+        self.len -= 1;
+
+        Some(removed)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ((&str, &str), &E)> {
+        Iter {
+            stack: vec![self.root.children.iter()],
+            entries: [].iter(),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &E> {
+        self.iter().map(|(_, e)| e)
+    }
+}
+
+impl<E: Clone + PartialEq> PersistentStateMap<E> {
+    /// Returns the changes needed to turn this state into `other`, like
+    /// `StateMap::diff`. Nodes the maps share are skipped, so diffing a map
+    /// against one derived from it only looks at the changed paths.
+    pub fn diff(&self, other: &PersistentStateMap<E>) -> StateDelta<E> {
+        let mut delta = StateDelta::default();
+        diff_branches(&self.root, &other.root, &mut delta);
+
+        delta.added.sort_by(|a, b| a.0.cmp(&b.0));
+        delta.removed.sort_by(|a, b| a.0.cmp(&b.0));
+        delta.changed.sort_by(|a, b| a.0.cmp(&b.0));
+
+        delta
+    }
+}
+
+fn diff_branches<E: Clone + PartialEq>(
+    old: &Branch<E>,
+    new: &Branch<E>,
+    delta: &mut StateDelta<E>,
+) {
+    for slot in 0..32 {
+        let bit = 1 << slot;
+        let old_child = if old.bitmap & bit != 0 {
+            Some(&old.children[old.position(bit)])
+        } else {
+            None
+        };
+        let new_child = if new.bitmap & bit != 0 {
+            Some(&new.children[new.position(bit)])
+        } else {
+            None
+        };
+
+        match (old_child, new_child) {
+            (Some(Node::Branch(o)), Some(Node::Branch(n))) => {
+                if !Arc::ptr_eq(o, n) {
+                    diff_branches(o, n, delta);
+                }
+            }
+            (Some(Node::Leaf(o)), Some(Node::Leaf(n))) if Arc::ptr_eq(o, n) => {}
+            (old_child, new_child) => {
+                // Slots that differ in shape are small, so compare their
+                // entries directly.
+                let mut old_entries = Vec::new();
+                let mut new_entries = Vec::new();
+                if let Some(node) = old_child {
+                    node_entries(node, &mut old_entries);
+                }
+                if let Some(node) = new_child {
+                    node_entries(node, &mut new_entries);
+                }
+
+                diff_entries(&old_entries, &new_entries, delta);
+            }
+        }
+    }
+}
+
+fn node_entries<'a, E>(node: &'a Node<E>, entries: &mut Vec<&'a Entry<E>>) {
+    match *node {
+        Node::Leaf(ref leaf) => entries.extend(leaf.entries.iter()),
+        Node::Branch(ref branch) => {
+            for child in &branch.children {
+                node_entries(child, entries);
+            }
+        }
+    }
+}
+
+fn diff_entries<E: Clone + PartialEq>(
+    old: &[&Entry<E>],
+    new: &[&Entry<E>],
+    delta: &mut StateDelta<E>,
+) {
+    let key = |e: &Entry<E>| (e.etype.to_string(), e.state_key.to_string());
+
+    for o in old {
+        match new.iter().find(|n| n.is(o.etype, o.state_key)) {
+            None => delta.removed.push((key(o), o.value.clone())),
+            Some(n) if n.value != o.value => {
+                delta.changed.push((key(o), o.value.clone(), n.value.clone()))
+            }
+            Some(_) => {}
+        }
+    }
+
+    for n in new {
+        if !old.iter().any(|o| o.is(n.etype, n.state_key)) {
+            delta.added.push((key(n), n.value.clone()));
+        }
+    }
+}
+
+impl<E: Clone> Default for PersistentStateMap<E> {
+    fn default() -> PersistentStateMap<E> {
+        PersistentStateMap::new()
+    }
+}
+
+struct Iter<'a, E: 'a> {
+    stack: Vec<slice::Iter<'a, Node<E>>>,
+    entries: slice::Iter<'a, Entry<E>>,
+}
+
+impl<'a, E> Iterator for Iter<'a, E> {
+    type Item = ((&'a str, &'a str), &'a E);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some((
+                    (entry.etype.as_str(), entry.state_key.as_str()),
+                    &entry.value,
+                ));
+            }
+
+            let node = loop {
+                match self.stack.last_mut()?.next() {
+                    Some(node) => break node,
+                    None => {
+                        self.stack.pop();
+                    }
+                }
+            };
+
+            match *node {
+                Node::Leaf(ref leaf) => self.entries = leaf.entries.iter(),
+                Node::Branch(ref branch) => self.stack.push(branch.children.iter()),
+            }
+        }
+    }
+}
+
+impl<E: Clone + fmt::Debug> fmt::Debug for PersistentStateMap<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, E: Clone> FromIterator<((&'a str, &'a str), E)> for PersistentStateMap<E> {
+    fn from_iter<T: IntoIterator<Item = ((&'a str, &'a str), E)>>(
+        iter: T,
+    ) -> PersistentStateMap<E> {
+        let mut state_map = PersistentStateMap::new();
+
+        for ((t, s), e) in iter {
+            state_map.insert(t, s, e);
+        }
+
+        state_map
+    }
+}
+
+// Nodes are shared between maps, so each map is charged its share of every
+// node it references. Summed over all the maps that share nodes this gives
+// the actual memory used.
+impl<E: HeapSizeOf> HeapSizeOf for PersistentStateMap<E> {
+    fn heap_size_of_children(&self) -> usize {
+        branch_size(&self.root, 1.0) as usize
+    }
+}
+
+fn branch_size<E: HeapSizeOf>(branch: &Arc<Branch<E>>, share: f64) -> f64 {
+    let share = share / Arc::strong_count(branch) as f64;
+
+    let size = mem::size_of::<Branch<E>>()
+        + branch.children.capacity() * mem::size_of::<Node<E>>();
+
+    let children: f64 = branch
+        .children
+        .iter()
+        .map(|child| match *child {
+            Node::Leaf(ref leaf) => leaf_size(leaf, share),
+            Node::Branch(ref branch) => branch_size(branch, share),
+        })
+        .sum();
+
+    size as f64 * share + children
+}
+
+fn leaf_size<E: HeapSizeOf>(leaf: &Arc<Leaf<E>>, share: f64) -> f64 {
+    let share = share / Arc::strong_count(leaf) as f64;
+
+    let size = mem::size_of::<Leaf<E>>()
+        + leaf.entries.capacity() * mem::size_of::<Entry<E>>()
+        + leaf.entries
+            .iter()
+            .map(|e| e.value.heap_size_of_children())
+            .sum::<usize>();
+
+    size as f64 * share
+}
+
+#[test]
+fn test_persistent_state_map() {
+    let mut state: PersistentStateMap<u32> = PersistentStateMap::new();
+    for i in 0..1000 {
+        state.insert("m.room.member", &format!("@{}:a", i), i);
+    }
+    state.insert("m.room.create", "", 1000);
+
+    let mut changed = state.clone();
+    assert_eq!(changed.insert("m.room.member", "@5:a", 5000), Some(5));
+    assert_eq!(changed.remove("m.room.member", "@6:a"), Some(6));
+    assert_eq!(changed.remove("m.room.member", "@6:a"), None);
+    changed.insert("m.room.topic", "", 2000);
+
+    // The original is unaffected.
+    assert_eq!(state.len(), 1001);
+    assert_eq!(state.get("m.room.member", "@5:a"), Some(&5));
+    assert_eq!(state.get("m.room.member", "@6:a"), Some(&6));
+    assert_eq!(state.get("m.room.topic", ""), None);
+
+    assert_eq!(changed.len(), 1001);
+    assert_eq!(changed.get("m.room.member", "@5:a"), Some(&5000));
+    assert_eq!(changed.get("m.room.member", "@6:a"), None);
+    assert_eq!(changed.get("m.room.topic", ""), Some(&2000));
+    assert_eq!(changed.iter().count(), 1001);
+
+    let state_map = changed.to_state_map();
+    assert_eq!(state_map.get("m.room.member", "@5:a"), Some(&5000));
+    assert_eq!(state_map.values().count(), 1001);
+
+    // Most of the trie is shared, so the two maps together should take
+    // little more than one.
+    let total = state.heap_size_of_children() + changed.heap_size_of_children();
+    let unshared = PersistentStateMap::from_state_map(&state_map).heap_size_of_children();
+    assert!(total < unshared + unshared / 4);
+    // Diffing only finds the changes, whichever way round.
+    let delta = state.diff(&changed);
+    assert_eq!(
+        delta.added,
+        vec![(("m.room.topic".to_string(), "".to_string()), 2000)]
+    );
+    assert_eq!(
+        delta.removed,
+        vec![(("m.room.member".to_string(), "@6:a".to_string()), 6)]
+    );
+    assert_eq!(
+        delta.changed,
+        vec![(("m.room.member".to_string(), "@5:a".to_string()), 5, 5000)]
+    );
+    assert_eq!(delta, state.to_state_map().diff(&state_map));
+    assert!(changed.diff(&changed.clone()).is_empty());
+
+    // The maps can be shared between threads.
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+    assert_send_sync(&state);
+}
@@ -0,0 +1,248 @@
+//! Parsing of `m.room.power_levels` event content.
+
+use std::collections::HashMap;
+
+use serde_json::{self, Value};
+
+use auth::AuthError;
+use room_version::RoomVersion;
+
+/// How power level values are parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegerParsing {
+    /// Only JSON integers are accepted, as required by v10+ rooms.
+    Strict,
+    /// Floats are also accepted and truncated, as are strings containing
+    /// integers.
+    Lenient,
+}
+
+impl IntegerParsing {
+    pub fn for_room_version(room_version: &RoomVersion) -> IntegerParsing {
+        if room_version.enforce_int_power_levels {
+            IntegerParsing::Strict
+        } else {
+            IntegerParsing::Lenient
+        }
+    }
+
+    /// Parses the value as an integer, returning `None` if it isn't one.
+    pub fn parse(self, value: &Value) -> Option<i64> {
+        match (self, value) {
+            (IntegerParsing::Strict, Value::Number(n)) => n.as_i64(),
+            (IntegerParsing::Lenient, Value::Number(n)) => {
+                n.as_i64().or_else(|| n.as_f64().map(|f| f as i64))
+            }
+            (IntegerParsing::Lenient, Value::String(s)) => s.trim().parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+/// The parsed content of an `m.room.power_levels` event.
+///
+/// Levels that are missing or can't be parsed are left unset, so that the
+/// defaults apply.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PowerLevels {
+    pub users: HashMap<String, i64>,
+    pub users_default: Option<i64>,
+
+    pub events: HashMap<String, i64>,
+    pub events_default: Option<i64>,
+    pub state_default: Option<i64>,
+
+    pub ban: Option<i64>,
+    pub kick: Option<i64>,
+    pub invite: Option<i64>,
+    pub redact: Option<i64>,
+
+    pub notifications_room: Option<i64>,
+}
+
+/// The top level keys that hold a single level.
+pub const LEVEL_KEYS: [&str; 7] = [
+    "users_default",
+    "events_default",
+    "state_default",
+    "ban",
+    "kick",
+    "redact",
+    "invite",
+];
+
+impl PowerLevels {
+    pub fn from_content(
+        content: &serde_json::Map<String, Value>,
+        parsing: IntegerParsing,
+    ) -> PowerLevels {
+        let get_map = |key: &str| -> HashMap<String, i64> {
+            content
+                .get(key)
+                .and_then(Value::as_object)
+                .map(|entries| {
+                    entries
+                        .iter()
+                        .filter_map(|(k, v)| parsing.parse(v).map(|v| (k.clone(), v)))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let get_level = |key: &str| content.get(key).and_then(|v| parsing.parse(v));
+
+        PowerLevels {
+            users: get_map("users"),
+            users_default: get_level("users_default"),
+            events: get_map("events"),
+            events_default: get_level("events_default"),
+            state_default: get_level("state_default"),
+            ban: get_level("ban"),
+            kick: get_level("kick"),
+            invite: get_level("invite"),
+            redact: get_level("redact"),
+            notifications_room: content
+                .get("notifications")
+                .and_then(|n| n.get("room"))
+                .and_then(|v| parsing.parse(v)),
+        }
+    }
+
+    /// Checks that the content only contains valid power levels.
+    ///
+    /// The `users` key must always map to integers. When parsing strictly,
+    /// `events`, `notifications` and the top level levels must also be
+    /// integers.
+    pub fn validate_content(
+        content: &serde_json::Map<String, Value>,
+        parsing: IntegerParsing,
+    ) -> Result<(), AuthError> {
+        let mut maps = vec!["users"];
+
+        if parsing == IntegerParsing::Strict {
+            maps.push("events");
+            maps.push("notifications");
+
+            for key in &LEVEL_KEYS {
+                if let Some(value) = content.get(*key) {
+                    if parsing.parse(value).is_none() {
+                        return Err(AuthError::InvalidPowerLevels);
+                    }
+                }
+            }
+        }
+
+        for key in maps {
+            let entries = match content.get(key) {
+                Some(Value::Object(entries)) => entries,
+                Some(_) => return Err(AuthError::InvalidPowerLevels),
+                None => continue,
+            };
+
+            if entries.values().any(|v| parsing.parse(v).is_none()) {
+                return Err(AuthError::InvalidPowerLevels);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Gets one of the top level levels, e.g. `ban`, by name.
+    pub fn named_level(&self, name: &str) -> Option<i64> {
+        match name {
+            "users_default" => self.users_default,
+            "events_default" => self.events_default,
+            "state_default" => self.state_default,
+            "ban" => self.ban,
+            "kick" => self.kick,
+            "invite" => self.invite,
+            "redact" => self.redact,
+            _ => None,
+        }
+    }
+
+    pub fn user_level(&self, user: &str) -> i64 {
+        self.users
+            .get(user)
+            .cloned()
+            .unwrap_or_else(|| self.users_default.unwrap_or(0))
+    }
+
+    /// The level required to send an event of the given type.
+    pub fn send_level(&self, etype: &str, is_state: bool) -> i64 {
+        let default = if is_state {
+            self.state_default.unwrap_or(50)
+        } else {
+            self.events_default.unwrap_or(0)
+        };
+
+        self.events.get(etype).cloned().unwrap_or(default)
+    }
+}
+
+#[test]
+fn test_power_levels_from_content() {
+    let content: serde_json::Map<String, Value> = serde_json::from_str(
+        r#"{"users": {"@a:a": 100, "@b:a": "50", "@c:a": null}, "users_default": 10,
+        "events": {"m.room.name": 75}, "ban": "foo", "notifications": {"room": 20}}"#,
+    )
+    .unwrap();
+
+    let power_levels = PowerLevels::from_content(&content, IntegerParsing::Lenient);
+
+    assert_eq!(power_levels.user_level("@a:a"), 100);
+    assert_eq!(power_levels.user_level("@b:a"), 50);
+    assert_eq!(power_levels.user_level("@c:a"), 10);
+    assert_eq!(power_levels.named_level("ban"), None);
+    assert_eq!(power_levels.send_level("m.room.name", true), 75);
+    assert_eq!(power_levels.send_level("m.room.topic", true), 50);
+    assert_eq!(power_levels.send_level("m.room.message", false), 0);
+    assert_eq!(power_levels.notifications_room, Some(20));
+
+    assert_eq!(
+        PowerLevels::validate_content(&content, IntegerParsing::Lenient),
+        Err(AuthError::InvalidPowerLevels)
+    );
+
+    // Only `users` is validated in older rooms.
+    let content: serde_json::Map<String, Value> =
+        serde_json::from_str(r#"{"users": {"@a:a": 100}, "events": {"m.room.name": null}}"#)
+            .unwrap();
+    assert!(PowerLevels::validate_content(&content, IntegerParsing::Lenient).is_ok());
+    assert_eq!(
+        PowerLevels::validate_content(&content, IntegerParsing::Strict),
+        Err(AuthError::InvalidPowerLevels)
+    );
+}
+
+#[test]
+fn test_integer_parsing() {
+    let content: serde_json::Map<String, Value> = serde_json::from_str(
+        r#"{"users": {"@a:a": 100, "@b:a": " 50"}, "ban": 1.5, "kick": "75"}"#,
+    )
+    .unwrap();
+
+    let lenient = PowerLevels::from_content(&content, IntegerParsing::Lenient);
+    assert_eq!(lenient.user_level("@b:a"), 50);
+    assert_eq!(lenient.ban, Some(1));
+    assert_eq!(lenient.kick, Some(75));
+    assert!(PowerLevels::validate_content(&content, IntegerParsing::Lenient).is_ok());
+
+    let strict = PowerLevels::from_content(&content, IntegerParsing::Strict);
+    assert_eq!(strict.user_level("@b:a"), 0);
+    assert_eq!(strict.kick, None);
+    assert_eq!(
+        PowerLevels::validate_content(&content, IntegerParsing::Strict),
+        Err(AuthError::InvalidPowerLevels)
+    );
+}
+
+#[test]
+fn test_parse_integer_values() {
+    let values: Vec<Value> = serde_json::from_str(r#"[50, "50", " 50 ", 50.0, "5x"]"#).unwrap();
+
+    let lenient: Vec<_> = values.iter().map(|v| IntegerParsing::Lenient.parse(v)).collect();
+    assert_eq!(lenient, vec![Some(50), Some(50), Some(50), Some(50), None]);
+
+    let strict: Vec<_> = values.iter().map(|v| IntegerParsing::Strict.parse(v)).collect();
+    assert_eq!(strict, vec![Some(50), None, None, None, None]);
+}
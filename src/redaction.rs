@@ -0,0 +1,186 @@
+//! The redaction algorithm, which strips events down to the keys needed for
+//! auth and signature checks.
+
+use serde_json::{self, Map, Value};
+
+use auth::Event;
+use room_version::RoomVersion;
+
+/// Redacts the event, as per the given room version.
+pub fn redact(event: &Event, room_version: &RoomVersion) -> Event {
+    let json = event.to_json(room_version);
+    let redacted = redact_json(json.as_object().expect("event is an object"), room_version);
+
+    // The redaction algorithm keeps all the keys that are required to parse
+    // an event.
+    let mut redacted: Event =
+        serde_json::from_value(Value::Object(redacted)).expect("redacted event is valid");
+    redacted.event_id = event.event_id.clone();
+
+    redacted
+}
+
+/// Redacts the JSON form of an event, as per the given room version.
+pub fn redact_json(event: &Map<String, Value>, room_version: &RoomVersion) -> Map<String, Value> {
+    let mut allowed_keys = vec![
+        "event_id",
+        "type",
+        "room_id",
+        "sender",
+        "state_key",
+        "hashes",
+        "signatures",
+        "depth",
+        "prev_events",
+        "auth_events",
+        "origin_server_ts",
+    ];
+
+    if !room_version.updated_redaction_rules {
+        allowed_keys.extend(&["prev_state", "origin", "membership"]);
+    }
+
+    let mut redacted: Map<String, Value> = event
+        .iter()
+        .filter(|&(k, _)| allowed_keys.contains(&(k as &str)))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+
+    let etype = event.get("type").and_then(Value::as_str).unwrap_or("");
+    let content = event
+        .get("content")
+        .and_then(Value::as_object)
+        .map(|content| redact_content(etype, content, room_version))
+        .unwrap_or_default();
+
+    redacted.insert("content".into(), Value::Object(content));
+
+    redacted
+}
+
+/// Redacts the content of an event of the given type, as per the given room
+/// version.
+pub fn redact_content(
+    etype: &str,
+    content: &Map<String, Value>,
+    room_version: &RoomVersion,
+) -> Map<String, Value> {
+    let mut allowed_keys = Vec::new();
+
+    match etype {
+        "m.room.member" => {
+            allowed_keys.push("membership");
+
+            if room_version.restricted_join_rule_fix {
+                allowed_keys.push("join_authorised_via_users_server");
+            }
+        }
+        "m.room.create" => {
+            if room_version.updated_redaction_rules {
+                return content.clone();
+            }
+
+            allowed_keys.push("creator");
+        }
+        "m.room.join_rules" => {
+            allowed_keys.push("join_rule");
+
+            if room_version.restricted_join_rule {
+                allowed_keys.push("allow");
+            }
+        }
+        "m.room.power_levels" => {
+            allowed_keys.extend(&[
+                "ban",
+                "events",
+                "events_default",
+                "kick",
+                "redact",
+                "state_default",
+                "users",
+                "users_default",
+            ]);
+
+            if room_version.updated_redaction_rules {
+                allowed_keys.push("invite");
+            }
+        }
+        "m.room.aliases" if room_version.special_case_aliases_auth => allowed_keys.push("aliases"),
+        "m.room.history_visibility" => allowed_keys.push("history_visibility"),
+        "m.room.redaction" if room_version.updated_redaction_rules => allowed_keys.push("redacts"),
+        _ => {}
+    }
+
+    let mut redacted: Map<String, Value> = content
+        .iter()
+        .filter(|&(k, _)| allowed_keys.contains(&(k as &str)))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+
+    // Only the signed part of a third party invite is kept.
+    if etype == "m.room.member" && room_version.updated_redaction_rules {
+        if let Some(signed) = content
+            .get("third_party_invite")
+            .and_then(|t| t.get("signed"))
+        {
+            let mut third_party_invite = Map::new();
+            third_party_invite.insert("signed".into(), signed.clone());
+
+            redacted.insert(
+                "third_party_invite".into(),
+                Value::Object(third_party_invite),
+            );
+        }
+    }
+
+    redacted
+}
+
+#[test]
+fn test_redact_json() {
+    let event: Value = serde_json::from_str(
+        r#"{"type": "m.room.join_rules", "sender": "@a:a", "origin": "a", "unsigned": {},
+        "content": {"join_rule": "restricted", "allow": [], "foo": "bar"}}"#,
+    )
+    .unwrap();
+    let event = event.as_object().unwrap();
+
+    let redacted = redact_json(event, &RoomVersion::V1);
+    let expected: Value = serde_json::from_str(
+        r#"{"type": "m.room.join_rules", "sender": "@a:a", "origin": "a",
+        "content": {"join_rule": "restricted"}}"#,
+    )
+    .unwrap();
+    assert_eq!(Value::Object(redacted), expected);
+
+    let redacted = redact_json(event, &RoomVersion::V11);
+    let expected: Value = serde_json::from_str(
+        r#"{"type": "m.room.join_rules", "sender": "@a:a",
+        "content": {"join_rule": "restricted", "allow": []}}"#,
+    )
+    .unwrap();
+    assert_eq!(Value::Object(redacted), expected);
+}
+
+#[test]
+fn test_redact() {
+    let event: Event = serde_json::from_str(
+        r#"{"sender": "@a:a", "room_id": "!r:a", "event_id": "$1:a", "type": "m.room.power_levels",
+        "state_key": "", "prev_events": [], "auth_events": [], "depth": 3, "origin": "a",
+        "content": {"users": {"@a:a": 100}, "invite": 0, "notifications": {"room": 0}}}"#,
+    )
+    .unwrap();
+
+    let redacted = redact(&event, &RoomVersion::V1);
+    assert_eq!(redacted.event_id, "$1:a");
+    assert_eq!(redacted.state_key.as_ref().map(|s| s as &str), Some(""));
+    assert_eq!(redacted.content.keys().collect::<Vec<_>>(), vec!["users"]);
+    assert!(redacted.other.contains_key("origin"));
+
+    let redacted = redact(&event, &RoomVersion::V11);
+    assert_eq!(
+        redacted.content.keys().collect::<Vec<_>>(),
+        vec!["invite", "users"]
+    );
+    assert!(!redacted.other.contains_key("origin"));
+}
@@ -0,0 +1,655 @@
+use std::collections::{HashMap, HashSet};
+use std::io::BufRead;
+
+use failure::Error;
+use heapsize::HeapSizeOf;
+use serde_json;
+
+use auth::{self, get_domain_from_id, Event, EventReference};
+use auth_chain::{get_auth_events, get_auth_events_from_state};
+use event_size::check_event_size;
+use hashes;
+use intern::EventHandle;
+use persistent_state_map::PersistentStateMap;
+use redaction::redact;
+use room_version::RoomVersion;
+use state;
+use state_group_store::StateGroupStore;
+use state_map::StateMap;
+
+/// The DAG of events in a room, built up by ingesting events one at a time.
+#[derive(Debug, Default)]
+pub struct RoomDag {
+    /// event_id -> event
+    pub event_map: HashMap<EventHandle, Event>,
+    /// event_id -> set of event_ids that reference it as a prev event
+    pub parents: HashMap<EventHandle, HashSet<EventHandle>>,
+    /// Set of forward extremities
+    pub extremities: HashSet<EventHandle>,
+    /// Set of events that have no prev events
+    pub roots: Vec<EventHandle>,
+}
+
+impl RoomDag {
+    pub fn new() -> RoomDag {
+        RoomDag::default()
+    }
+
+    /// Build a DAG from a reader containing one JSON event per line.
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<RoomDag, Error> {
+        let mut events = Vec::new();
+
+        for line in reader.lines() {
+            let line = line?;
+            let event: Event = serde_json::from_str(&line)?;
+            events.push(event);
+        }
+
+        // Events in v3+ rooms don't have an event_id, so we need to know the
+        // room version to calculate them.
+        if events.iter().any(|ev| ev.event_id.is_empty()) {
+            let create_event = events
+                .iter()
+                .find(|ev| is_create_event(ev))
+                .ok_or_else(|| format_err!("no create event"))?;
+            let room_version = RoomVersion::from_create_event(create_event)?;
+
+            for event in &mut events {
+                if event.event_id.is_empty() {
+                    event.event_id = hashes::compute_event_id(event, &room_version)?;
+                }
+            }
+        }
+
+        let mut dag = RoomDag::new();
+        for event in events {
+            dag.add_event(event);
+        }
+
+        Ok(dag)
+    }
+
+    pub fn add_event(&mut self, mut event: Event) {
+        // We never look at `unsigned`, and it can be large, so drop it.
+        event.other.remove("unsigned");
+
+        let event_id = event.handle();
+
+        for eid in event.prev_events.iter().map(EventReference::handle) {
+            self.extremities.remove(&eid);
+            self.parents
+                .entry(eid)
+                .or_default()
+                .insert(event_id);
+        }
+
+        if !self.parents.contains_key(&event_id) {
+            self.extremities.insert(event_id);
+        }
+
+        if event.prev_events.is_empty() {
+            self.roots.push(event_id);
+        }
+
+        self.event_map.insert(event_id, event);
+    }
+
+    /// Get the room version from the room's create event.
+    pub fn room_version(&self) -> Result<RoomVersion, Error> {
+        let create_event = self.event_map
+            .values()
+            .find(|ev| is_create_event(ev))
+            .ok_or_else(|| format_err!("no create event"))?;
+
+        RoomVersion::from_create_event(create_event)
+    }
+
+    /// Replace state events that have been redacted with their redacted form,
+    /// as that's what servers use for auth. Returns the IDs of the redacted
+    /// events.
+    pub fn apply_redactions(&mut self, room_version: &RoomVersion) -> Vec<EventHandle> {
+        let mut to_redact = Vec::new();
+
+        for redaction in self.event_map.values() {
+            if redaction.etype != "m.room.redaction" {
+                continue;
+            }
+
+            let redacts = if let Some(redacts) = redaction.redacts() {
+                redacts
+            } else {
+                continue;
+            };
+
+            let target = if let Some(target) =
+                EventHandle::get(redacts).and_then(|eid| self.event_map.get(&eid))
+            {
+                target
+            } else {
+                continue;
+            };
+
+            if target.state_key.is_none() || target.room_id != redaction.room_id {
+                continue;
+            }
+
+            let auth_events = get_auth_events(redaction, &self.event_map);
+            let allowed = match auth::check_redaction(redaction, &auth_events, room_version) {
+                Ok(false) => true,
+                Ok(true) => {
+                    let redaction_domain = get_domain_from_id(&redaction.sender).ok();
+                    redaction_domain.is_some()
+                        && redaction_domain == get_domain_from_id(&target.sender).ok()
+                }
+                Err(_) => false,
+            };
+
+            if allowed {
+                to_redact.push(target.handle());
+            }
+        }
+
+        to_redact.sort_by_key(|eid| eid.as_str());
+        to_redact.dedup();
+
+        for event_id in &to_redact {
+            let redacted = redact(&self.event_map[event_id], room_version);
+            self.event_map.insert(*event_id, redacted);
+        }
+
+        to_redact
+    }
+
+    /// Events that are referenced as prev events but that we don't have.
+    pub fn missing(&self) -> impl Iterator<Item = EventHandle> + '_ {
+        self.parents
+            .keys()
+            .filter(move |r| !self.event_map.contains_key(*r))
+            .cloned()
+    }
+
+    /// Return list of events in topological ordering, with root first.
+    pub fn get_ordered(&self) -> Vec<EventHandle> {
+        let mut ordered = Vec::with_capacity(self.event_map.len());
+
+        let mut zeroes: Vec<EventHandle> = self.extremities.iter().cloned().collect();
+        let mut adjacents: HashMap<EventHandle, usize> = self.event_map
+            .keys()
+            .map(|key| {
+                (
+                    *key,
+                    self.parents.get(key).map(HashSet::len).unwrap_or(0),
+                )
+            })
+            .collect();
+
+        while let Some(event_id) = zeroes.pop() {
+            ordered.push(event_id);
+
+            for p in &self.event_map[&event_id].prev_events {
+                let p = p.handle();
+
+                let a = if let Some(i) = adjacents.get_mut(&p) {
+                    *i -= 1;
+                    *i
+                } else {
+                    1
+                };
+
+                if a == 0 {
+                    adjacents.remove(&p);
+                    zeroes.push(p);
+                }
+            }
+        }
+
+        assert_eq!(ordered.len(), self.event_map.len());
+
+        ordered.reverse();
+        ordered
+    }
+}
+
+fn is_create_event(event: &Event) -> bool {
+    event.etype == "m.room.create" && event.state_key.as_ref().map(|s| s as &str) == Some("")
+}
+
+/// The computed state of every event in a room.
+///
+/// Multiple events may share the same state, so the state is given an ID
+/// called "state group". We map event_id -> sg, and store the state of each
+/// group delta encoded, as Synapse does.
+#[derive(Debug, Default)]
+pub struct StateGroups {
+    pub event_to_sg: HashMap<EventHandle, i32>,
+    /// sg -> the sg it was derived from by adding a single event's state,
+    /// like Synapse's `state_group_edges`.
+    pub prev_groups: HashMap<i32, i32>,
+    pub store: StateGroupStore,
+    /// Events that failed auth against the state before them, and so didn't
+    /// update the state.
+    pub rejected: HashSet<EventHandle>,
+}
+
+impl HeapSizeOf for StateGroups {
+    fn heap_size_of_children(&self) -> usize {
+        self.event_to_sg.heap_size_of_children()
+            + self.prev_groups.heap_size_of_children()
+            + self.store.heap_size_of_children()
+            + self.rejected.heap_size_of_children()
+    }
+}
+
+impl StateGroups {
+    /// Get the state at the given event, if we've calculated it.
+    pub fn get_state(&self, event_id: EventHandle) -> Option<PersistentStateMap<EventHandle>> {
+        self.event_to_sg
+            .get(&event_id)
+            .and_then(|sg| self.store.get_state(*sg))
+    }
+
+    /// Get the resolved state after the given events, e.g. the state before
+    /// an event given its prev events. Events we haven't calculated the state
+    /// for are ignored.
+    pub fn resolve_state_at<I>(
+        &self,
+        event_ids: I,
+        event_map: &HashMap<EventHandle, Event>,
+        room_version: &RoomVersion,
+    ) -> PersistentStateMap<EventHandle>
+    where
+        I: IntoIterator<Item = EventHandle>,
+    {
+        let state_sets: Vec<_> = event_ids
+            .into_iter()
+            .filter_map(|eid| self.get_state(eid))
+            .collect();
+
+        match state_sets.len() {
+            0 => PersistentStateMap::new(),
+            1 => state_sets[0].clone(),
+            _ => resolve_persistent_state(&state_sets, event_map, room_version),
+        }
+    }
+}
+
+/// Resolves persistent states, which state resolution works on as plain
+/// `StateMap`s.
+fn resolve_persistent_state(
+    state_sets: &[PersistentStateMap<EventHandle>],
+    event_map: &HashMap<EventHandle, Event>,
+    room_version: &RoomVersion,
+) -> PersistentStateMap<EventHandle> {
+    let state_sets: Vec<StateMap<EventHandle>> = state_sets
+        .iter()
+        .map(|state| state.to_state_map())
+        .collect();
+
+    let resolved = state::resolve_state(state_sets.iter().collect(), event_map, room_version);
+
+    PersistentStateMap::from_state_map(&resolved)
+}
+
+/// Calculates the state at each event of a `RoomDag`.
+///
+/// Events must be fed in topological order, e.g. as returned by
+/// `RoomDag::get_ordered`. Events are authed against the state before them,
+/// and rejected events don't update the state.
+pub struct StateCalculator<'a> {
+    dag: &'a RoomDag,
+    room_version: RoomVersion,
+    next_sg: i32,
+    groups: StateGroups,
+    reject_oversized: bool,
+}
+
+impl<'a> StateCalculator<'a> {
+    pub fn new(dag: &'a RoomDag, room_version: RoomVersion) -> StateCalculator<'a> {
+        StateCalculator {
+            dag,
+            room_version,
+            next_sg: 0,
+            groups: StateGroups::default(),
+            reject_oversized: false,
+        }
+    }
+
+    /// Whether to reject events that exceed the size limits. Servers should
+    /// never have accepted them, but older ones didn't check all the limits.
+    pub fn set_reject_oversized(&mut self, reject_oversized: bool) {
+        self.reject_oversized = reject_oversized;
+    }
+
+    /// The maximum number of deltas between snapshots in the state group
+    /// store.
+    pub fn set_max_delta_chain(&mut self, max_delta_chain: usize) {
+        self.groups.store.set_max_delta_chain(max_delta_chain);
+    }
+
+    /// Calculate the state for all events in the given ordering.
+    pub fn calculate(
+        dag: &RoomDag,
+        room_version: RoomVersion,
+        ordered: &[EventHandle],
+    ) -> StateGroups {
+        let mut calculator = StateCalculator::new(dag, room_version);
+
+        for eid in ordered {
+            calculator.process_event(*eid);
+        }
+
+        calculator.into_groups()
+    }
+
+    /// Calculate and store the state for the given event. All of its prev
+    /// events must have already been processed.
+    pub fn process_event(&mut self, eid: EventHandle) {
+        let event = &self.dag.event_map[&eid];
+
+        // Whether the state is the same as a previous state group.
+        let mut current_sg = None;
+        // The state groups of the prev events, which a new state group is
+        // stored as a delta against.
+        let mut prev_sgs = Vec::new();
+
+        // The block returns the new state if a new state group is needed.
+        let new_state = {
+            let event_to_sg = &self.groups.event_to_sg;
+            let store = &self.groups.store;
+            let rejected_events = &mut self.groups.rejected;
+
+            // Work out the resolved state for all prev_events
+            let mut state = if event.prev_events.len() > 1 {
+                let state_sets: Vec<_> = event
+                    .prev_events
+                    .iter()
+                    .map(EventReference::handle)
+                    .filter_map(|pid| {
+                        if let Some(&sg) = event_to_sg.get(&pid) {
+                            if let Some(state) = store.get_state(sg) {
+                                if !prev_sgs.contains(&sg) {
+                                    prev_sgs.push(sg);
+                                }
+                                Some(state)
+                            } else {
+                                panic!("Failed to find state for event: {}, {}", pid, eid);
+                            }
+                        } else {
+                            // panic!("Failed to find sg for event: {}, processing: {}", pid, eid);
+                            // println!("Ignoring event: {}", pid);
+                            None
+                        }
+                    })
+                    .collect();
+
+                resolve_persistent_state(&state_sets, &self.dag.event_map, &self.room_version)
+            } else if event.prev_events.len() == 1 {
+                let s = event_to_sg[&event.prev_events[0].handle()];
+                current_sg = Some(s);
+                prev_sgs.push(s);
+                store.get_state(s).expect("prev event has a state group")
+            } else {
+                PersistentStateMap::new()
+            };
+
+            let auth_events = get_auth_events_from_state(
+                event,
+                &state,
+                &self.dag.event_map,
+                &self.room_version,
+            );
+            let oversized = self.reject_oversized
+                && !check_event_size(event, &self.room_version).is_empty();
+            let rejected =
+                oversized || auth::check(event, &auth_events, &self.room_version).is_err();
+            if rejected {
+                rejected_events.insert(eid);
+            }
+
+            // If this is an accepted state event then we add it to the state
+            if let (Some(state_key), false) = (event.state_key.as_ref(), rejected) {
+                current_sg = None;
+                state.insert(&event.etype, state_key, eid);
+            }
+
+            // If nothing has changed we reuse the state group, otherwise
+            // create a new one.
+            if current_sg.is_some() {
+                None
+            } else {
+                Some(state)
+            }
+        };
+
+        let sg = if let Some(state) = new_state {
+            // We generated a new state group, so persist it.
+            self.next_sg += 1;
+            self.groups.store.insert(self.next_sg, &prev_sgs, state);
+            if let [prev_sg] = prev_sgs[..] {
+                self.groups.prev_groups.insert(self.next_sg, prev_sg);
+            }
+            self.next_sg
+        } else {
+            current_sg.expect("either reusing a state group or creating a new one")
+        };
+
+        self.groups.event_to_sg.insert(eid, sg);
+    }
+
+    pub fn groups(&self) -> &StateGroups {
+        &self.groups
+    }
+
+    pub fn into_groups(self) -> StateGroups {
+        self.groups
+    }
+}
+
+#[test]
+fn test_room_dag() {
+    use std::io::Cursor;
+
+    let lines = r#"
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$1:a", "type": "m.room.create", "state_key": "", "prev_events": [], "content": {"creator": "@a:a"}, "depth": 1}
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$2:a", "type": "m.room.member", "state_key": "@a:a", "prev_events": [["$1:a", {}]], "content": {"membership": "join"}, "depth": 2}
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$3:a", "type": "m.room.message", "prev_events": [["$2:a", {}]], "content": {}, "depth": 3}
+"#;
+
+    let dag = RoomDag::from_reader(Cursor::new(lines.trim())).unwrap();
+
+    let eid = EventHandle::intern;
+
+    assert_eq!(dag.roots, vec![eid("$1:a")]);
+    assert_eq!(dag.extremities.len(), 1);
+    assert!(dag.extremities.contains(&eid("$3:a")));
+    assert_eq!(dag.missing().count(), 0);
+
+    let ordered = dag.get_ordered();
+    assert_eq!(ordered, vec![eid("$1:a"), eid("$2:a"), eid("$3:a")]);
+
+    assert_eq!(dag.room_version().unwrap(), RoomVersion::V1);
+
+    let groups = StateCalculator::calculate(&dag, RoomVersion::V1, &ordered);
+
+    // The message event doesn't change the state so shares its state group
+    // with the membership event.
+    assert_eq!(groups.store.len(), 2);
+    assert_eq!(groups.event_to_sg[&eid("$2:a")], groups.event_to_sg[&eid("$3:a")]);
+
+    let state = groups.get_state(eid("$3:a")).unwrap();
+    assert_eq!(state.get("m.room.create", ""), Some(&eid("$1:a")));
+    assert_eq!(state.get("m.room.member", "@a:a"), Some(&eid("$2:a")));
+
+    // The membership event's group is stored as a delta against the create
+    // event's.
+    let sg = groups.event_to_sg[&eid("$3:a")];
+    assert_eq!(groups.store.chain_length(sg), Some(1));
+}
+
+#[test]
+fn test_state_groups_rebuilt_from_store() {
+    use state_group_store::StoredGroup;
+    use std::io::Cursor;
+
+    // The message merges the two forks, so gets a resolved state group.
+    let lines = r#"
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$1:a", "type": "m.room.create", "state_key": "", "prev_events": [], "content": {"creator": "@a:a"}, "depth": 1}
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$2:a", "type": "m.room.member", "state_key": "@a:a", "prev_events": [["$1:a", {}]], "content": {"membership": "join"}, "depth": 2}
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$3:a", "type": "m.room.power_levels", "state_key": "", "prev_events": [["$2:a", {}]], "content": {"users": {"@a:a": 100}}, "depth": 3}
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$4:a", "type": "m.room.join_rules", "state_key": "", "prev_events": [["$3:a", {}]], "content": {"join_rule": "public"}, "depth": 4}
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$5:a", "type": "m.room.topic", "state_key": "", "prev_events": [["$4:a", {}]], "content": {"topic": "A"}, "depth": 5}
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$6:a", "type": "m.room.name", "state_key": "", "prev_events": [["$4:a", {}]], "content": {"name": "B"}, "depth": 5}
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$7:a", "type": "m.room.message", "prev_events": [["$5:a", {}], ["$6:a", {}]], "content": {}, "depth": 6}
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$8:a", "type": "m.room.topic", "state_key": "", "prev_events": [["$7:a", {}]], "content": {"topic": "C"}, "depth": 7}
+"#;
+
+    let dag = RoomDag::from_reader(Cursor::new(lines.trim())).unwrap();
+    let ordered = dag.get_ordered();
+
+    let mut calculator = StateCalculator::new(&dag, RoomVersion::V1);
+    calculator.set_max_delta_chain(2);
+
+    let mut calculated = Vec::new();
+    for eid in &ordered {
+        calculator.process_event(*eid);
+        calculated.push((*eid, calculator.groups().get_state(*eid).unwrap()));
+    }
+
+    let mut groups = calculator.into_groups();
+    groups.store.set_cache_size(0);
+
+    for (eid, state) in calculated {
+        let rebuilt = groups.get_state(eid).unwrap();
+        assert!(rebuilt.diff(&state).is_empty(), "state of {} differs", eid);
+    }
+
+    let sg = |event_id| groups.event_to_sg[&EventHandle::intern(event_id)];
+
+    // The merge is stored against one of the forks.
+    match *groups.store.get(sg("$7:a")).unwrap() {
+        StoredGroup::Delta { prev_group, .. } => {
+            assert!(prev_group == sg("$5:a") || prev_group == sg("$6:a"))
+        }
+        StoredGroup::Snapshot(_) => panic!("expected the merge to be stored as a delta"),
+    }
+
+    // The chain would be too long, so the next group is a snapshot.
+    assert_eq!(groups.store.chain_length(sg("$8:a")), Some(0));
+}
+
+#[test]
+fn test_apply_redactions() {
+    use std::io::Cursor;
+
+    let lines = r#"
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$1:a", "type": "m.room.create", "state_key": "", "prev_events": [], "auth_events": [], "content": {"creator": "@a:a"}, "depth": 1}
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$2:a", "type": "m.room.member", "state_key": "@a:a", "prev_events": [["$1:a", {}]], "auth_events": [["$1:a", {}]], "content": {"membership": "join", "displayname": "A"}, "depth": 2}
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$3:a", "type": "m.room.join_rules", "state_key": "", "prev_events": [["$2:a", {}]], "auth_events": [["$1:a", {}], ["$2:a", {}]], "content": {"join_rule": "public", "foo": "bar"}, "depth": 3}
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$4:a", "type": "m.room.redaction", "redacts": "$3:a", "prev_events": [["$3:a", {}]], "auth_events": [["$1:a", {}], ["$2:a", {}]], "content": {}, "depth": 4}
+{"sender": "@b:b", "room_id": "!r:a", "event_id": "$5:b", "type": "m.room.redaction", "redacts": "$2:a", "prev_events": [["$4:a", {}]], "auth_events": [["$1:a", {}]], "content": {}, "depth": 5}
+"#;
+
+    let mut dag = RoomDag::from_reader(Cursor::new(lines.trim())).unwrap();
+
+    let eid = EventHandle::intern;
+
+    let redacted = dag.apply_redactions(&RoomVersion::V1);
+    assert_eq!(redacted, vec![eid("$3:a")]);
+
+    let join_rules = &dag.event_map[&eid("$3:a")];
+    assert_eq!(join_rules.content.len(), 1);
+    assert_eq!(join_rules.content["join_rule"], "public");
+
+    assert_eq!(dag.event_map[&eid("$2:a")].content.len(), 2);
+}
+
+#[test]
+fn test_room_dag_reference_hash_ids() {
+    use std::io::Cursor;
+
+    let lines = r#"
+{"sender": "@a:a", "room_id": "!r:a", "type": "m.room.create", "state_key": "", "prev_events": [], "auth_events": [], "content": {"creator": "@a:a", "room_version": "4"}, "depth": 1, "origin_server_ts": 1, "hashes": {"sha256": "abc"}}
+"#;
+
+    let dag = RoomDag::from_reader(Cursor::new(lines.trim())).unwrap();
+    let create_id = dag.roots[0].as_str();
+
+    assert!(create_id.starts_with('$'));
+    assert_eq!(create_id.len(), 44);
+    assert!(!create_id.contains('+') && !create_id.contains('/'));
+
+    // The ID doesn't depend on content that gets redacted.
+    let line = format!(
+        r#"{{"sender": "@a:a", "room_id": "!r:a", "type": "m.room.member", "state_key": "@a:a", "prev_events": ["{0}"], "auth_events": ["{0}"], "content": {{"membership": "join"}}, "depth": 2, "origin_server_ts": 2}}"#,
+        create_id
+    );
+    let lines = format!(
+        "{}\n{}\n{}",
+        lines.trim(),
+        line,
+        line.replace(r#""join"}"#, r#""join", "displayname": "A"}"#)
+    );
+
+    let dag = RoomDag::from_reader(Cursor::new(lines)).unwrap();
+    assert_eq!(dag.event_map.len(), 2);
+    assert_eq!(dag.room_version().unwrap(), RoomVersion::V4);
+    assert_eq!(dag.get_ordered()[0].as_str(), create_id);
+}
+
+#[test]
+fn test_rejected_events() {
+    use std::io::Cursor;
+
+    // Bob isn't allowed to change the power levels, so the state after his
+    // attempt should still be the original power levels.
+    let lines = r#"
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$1:a", "type": "m.room.create", "state_key": "", "prev_events": [], "content": {"creator": "@a:a"}, "depth": 1}
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$2:a", "type": "m.room.member", "state_key": "@a:a", "prev_events": [["$1:a", {}]], "content": {"membership": "join"}, "depth": 2}
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$3:a", "type": "m.room.power_levels", "state_key": "", "prev_events": [["$2:a", {}]], "content": {"users": {"@a:a": 100}}, "depth": 3}
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$4:a", "type": "m.room.join_rules", "state_key": "", "prev_events": [["$3:a", {}]], "content": {"join_rule": "public"}, "depth": 4}
+{"sender": "@b:b", "room_id": "!r:a", "event_id": "$5:b", "type": "m.room.member", "state_key": "@b:b", "prev_events": [["$4:a", {}]], "content": {"membership": "join"}, "depth": 5}
+{"sender": "@b:b", "room_id": "!r:a", "event_id": "$6:b", "type": "m.room.power_levels", "state_key": "", "prev_events": [["$5:b", {}]], "content": {"users": {"@b:b": 100}}, "depth": 6}
+"#;
+
+    let dag = RoomDag::from_reader(Cursor::new(lines.trim())).unwrap();
+    let ordered = dag.get_ordered();
+    let groups = StateCalculator::calculate(&dag, RoomVersion::V1, &ordered);
+
+    let eid = EventHandle::intern;
+
+    assert_eq!(groups.rejected.len(), 1);
+    assert!(groups.rejected.contains(&eid("$6:b")));
+
+    let state = groups.get_state(eid("$6:b")).unwrap();
+    assert_eq!(state.get("m.room.power_levels", ""), Some(&eid("$3:a")));
+    assert_eq!(groups.event_to_sg[&eid("$5:b")], groups.event_to_sg[&eid("$6:b")]);
+}
+
+#[test]
+fn test_server_acl() {
+    use std::io::Cursor;
+
+    // The ACL isn't one of the auth events, but servers still reject events
+    // from denied servers.
+    let lines = r#"
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$1:a", "type": "m.room.create", "state_key": "", "prev_events": [], "content": {"creator": "@a:a"}, "depth": 1}
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$2:a", "type": "m.room.member", "state_key": "@a:a", "prev_events": [["$1:a", {}]], "content": {"membership": "join"}, "depth": 2}
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$3:a", "type": "m.room.join_rules", "state_key": "", "prev_events": [["$2:a", {}]], "content": {"join_rule": "public"}, "depth": 3}
+{"sender": "@a:a", "room_id": "!r:a", "event_id": "$4:a", "type": "m.room.server_acl", "state_key": "", "prev_events": [["$3:a", {}]], "content": {"allow": ["*"], "deny": ["evil"]}, "depth": 4}
+{"sender": "@b:b", "room_id": "!r:a", "event_id": "$5:b", "type": "m.room.member", "state_key": "@b:b", "prev_events": [["$4:a", {}]], "content": {"membership": "join"}, "depth": 5}
+{"sender": "@e:evil", "room_id": "!r:a", "event_id": "$6:evil", "type": "m.room.member", "state_key": "@e:evil", "prev_events": [["$5:b", {}]], "content": {"membership": "join"}, "depth": 6}
+"#;
+
+    let dag = RoomDag::from_reader(Cursor::new(lines.trim())).unwrap();
+    let ordered = dag.get_ordered();
+    let groups = StateCalculator::calculate(&dag, RoomVersion::V1, &ordered);
+
+    let eid = EventHandle::intern;
+
+    assert_eq!(groups.rejected.len(), 1);
+    assert!(groups.rejected.contains(&eid("$6:evil")));
+
+    let state = groups.get_state(eid("$6:evil")).unwrap();
+    assert_eq!(state.get("m.room.member", "@b:b"), Some(&eid("$5:b")));
+    assert_eq!(state.get("m.room.member", "@e:evil"), None);
+}
@@ -0,0 +1,224 @@
+use std::fmt;
+
+use failure::Error;
+use auth::Event;
+use content::{CreateContent, EventContent};
+
+/// The format of events, which determines how event IDs are derived and how
+/// event references (e.g. `prev_events`) are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventFormat {
+    /// Events have an `event_id` field, and reference other events by
+    /// `[event_id, hashes]` pairs. Used by room versions 1 and 2.
+    V1,
+    /// Event IDs are the standard base64 reference hash of the event, and
+    /// references are plain event IDs. Used by room version 3.
+    V2,
+    /// As `V2`, but using URL safe base64. Used by room versions 4+.
+    V3,
+}
+
+/// The algorithm used to resolve conflicting state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateResolutionVersion {
+    V1,
+    V2,
+}
+
+/// The set of rules a room follows, as determined by the `room_version` in
+/// its create event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoomVersion {
+    pub identifier: &'static str,
+    pub event_format: EventFormat,
+    pub state_res: StateResolutionVersion,
+    /// Whether signing keys must be valid at the time the event was sent
+    /// (v5+).
+    pub enforce_key_validity: bool,
+    /// Whether `m.room.aliases` events have their own auth rules (v1-v5).
+    pub special_case_aliases_auth: bool,
+    /// Whether changes to `notifications` power levels need to be checked
+    /// (v6+).
+    pub limit_notifications_power_levels: bool,
+    /// Whether the `knock` join rule and membership are supported (v7+).
+    pub knock_join_rule: bool,
+    /// Whether the `restricted` join rule is supported (v8+).
+    pub restricted_join_rule: bool,
+    /// Whether `join_authorised_via_users_server` is kept when redacting
+    /// membership events (v9+).
+    pub restricted_join_rule_fix: bool,
+    /// Whether the `knock_restricted` join rule is supported (v10+).
+    pub knock_restricted_join_rule: bool,
+    /// Whether power levels must be integers, rather than strings (v10+).
+    pub enforce_int_power_levels: bool,
+    /// Whether the creator is the sender of the create event, rather than
+    /// the `creator` content key (v11+).
+    pub implicit_room_creator: bool,
+    /// Whether the v11 redaction algorithm is used.
+    pub updated_redaction_rules: bool,
+}
+
+impl RoomVersion {
+    pub const V1: RoomVersion = RoomVersion {
+        identifier: "1",
+        event_format: EventFormat::V1,
+        state_res: StateResolutionVersion::V1,
+        enforce_key_validity: false,
+        special_case_aliases_auth: true,
+        limit_notifications_power_levels: false,
+        knock_join_rule: false,
+        restricted_join_rule: false,
+        restricted_join_rule_fix: false,
+        knock_restricted_join_rule: false,
+        enforce_int_power_levels: false,
+        implicit_room_creator: false,
+        updated_redaction_rules: false,
+    };
+
+    pub const V2: RoomVersion = RoomVersion {
+        identifier: "2",
+        state_res: StateResolutionVersion::V2,
+        ..RoomVersion::V1
+    };
+
+    pub const V3: RoomVersion = RoomVersion {
+        identifier: "3",
+        event_format: EventFormat::V2,
+        ..RoomVersion::V2
+    };
+
+    pub const V4: RoomVersion = RoomVersion {
+        identifier: "4",
+        event_format: EventFormat::V3,
+        ..RoomVersion::V3
+    };
+
+    pub const V5: RoomVersion = RoomVersion {
+        identifier: "5",
+        enforce_key_validity: true,
+        ..RoomVersion::V4
+    };
+
+    pub const V6: RoomVersion = RoomVersion {
+        identifier: "6",
+        special_case_aliases_auth: false,
+        limit_notifications_power_levels: true,
+        ..RoomVersion::V5
+    };
+
+    pub const V7: RoomVersion = RoomVersion {
+        identifier: "7",
+        knock_join_rule: true,
+        ..RoomVersion::V6
+    };
+
+    pub const V8: RoomVersion = RoomVersion {
+        identifier: "8",
+        restricted_join_rule: true,
+        ..RoomVersion::V7
+    };
+
+    pub const V9: RoomVersion = RoomVersion {
+        identifier: "9",
+        restricted_join_rule_fix: true,
+        ..RoomVersion::V8
+    };
+
+    pub const V10: RoomVersion = RoomVersion {
+        identifier: "10",
+        knock_restricted_join_rule: true,
+        enforce_int_power_levels: true,
+        ..RoomVersion::V9
+    };
+
+    pub const V11: RoomVersion = RoomVersion {
+        identifier: "11",
+        implicit_room_creator: true,
+        updated_redaction_rules: true,
+        ..RoomVersion::V10
+    };
+
+    pub const KNOWN: &'static [RoomVersion] = &[
+        RoomVersion::V1,
+        RoomVersion::V2,
+        RoomVersion::V3,
+        RoomVersion::V4,
+        RoomVersion::V5,
+        RoomVersion::V6,
+        RoomVersion::V7,
+        RoomVersion::V8,
+        RoomVersion::V9,
+        RoomVersion::V10,
+        RoomVersion::V11,
+    ];
+
+    pub fn from_identifier(identifier: &str) -> Option<RoomVersion> {
+        RoomVersion::KNOWN
+            .iter()
+            .find(|v| v.identifier == identifier)
+            .cloned()
+    }
+
+    /// Get the room version from the room's create event. Rooms without a
+    /// `room_version` are version 1.
+    pub fn from_create_event(event: &Event) -> Result<RoomVersion, Error> {
+        ensure!(event.etype == "m.room.create", "not a create event");
+
+        match event.typed_content() {
+            Ok(&EventContent::Create(CreateContent {
+                room_version: Some(ref identifier),
+                ..
+            })) => RoomVersion::from_identifier(identifier)
+                .ok_or_else(|| format_err!("unknown room version {}", identifier)),
+            Ok(_) => Ok(RoomVersion::V1),
+            Err(_) => bail!("invalid room version"),
+        }
+    }
+
+    /// Get the user ID of the room creator from the create event.
+    pub fn get_creator<'a>(&self, create_event: &'a Event) -> Option<&'a str> {
+        if self.implicit_room_creator {
+            Some(&create_event.sender)
+        } else {
+            match create_event.typed_content() {
+                Ok(EventContent::Create(content)) => content.creator.as_deref(),
+                _ => None,
+            }
+        }
+    }
+}
+
+impl Default for RoomVersion {
+    fn default() -> RoomVersion {
+        RoomVersion::V1
+    }
+}
+
+impl fmt::Display for RoomVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.identifier)
+    }
+}
+
+#[test]
+fn test_from_create_event() {
+    use serde_json;
+
+    let event: Event = serde_json::from_str(
+        r#"{"sender": "@a:a", "room_id": "!r:a", "event_id": "$1:a", "type": "m.room.create",
+        "state_key": "", "prev_events": [], "content": {"room_version": "11"}, "depth": 1}"#,
+    ).unwrap();
+
+    let room_version = RoomVersion::from_create_event(&event).unwrap();
+    assert_eq!(room_version, RoomVersion::V11);
+    assert_eq!(room_version.get_creator(&event), Some("@a:a"));
+
+    let event: Event = serde_json::from_str(
+        r#"{"sender": "@a:a", "room_id": "!r:a", "event_id": "$1:a", "type": "m.room.create",
+        "state_key": "", "prev_events": [], "content": {"creator": "@b:a"}, "depth": 1}"#,
+    ).unwrap();
+
+    let room_version = RoomVersion::from_create_event(&event).unwrap();
+    assert_eq!(room_version, RoomVersion::V1);
+    assert_eq!(room_version.get_creator(&event), Some("@b:a"));
+}
@@ -0,0 +1,142 @@
+//! Evaluation of `m.room.server_acl` events.
+
+use std::net::Ipv4Addr;
+
+use serde_json::Value;
+
+use auth::Event;
+
+/// The server ACL of a room, as given by its `m.room.server_acl` event.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerAcl {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    pub allow_ip_literals: bool,
+}
+
+impl ServerAcl {
+    /// Parse the ACL from the event content, ignoring invalid entries.
+    pub fn from_event(event: &Event) -> ServerAcl {
+        let get_list = |key: &str| -> Vec<String> {
+            event
+                .content
+                .get(key)
+                .and_then(Value::as_array)
+                .map(|entries| {
+                    entries
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        ServerAcl {
+            allow: get_list("allow"),
+            deny: get_list("deny"),
+            allow_ip_literals: event
+                .content
+                .get("allow_ip_literals")
+                .and_then(Value::as_bool)
+                .unwrap_or(true),
+        }
+    }
+
+    /// Whether the server is allowed to participate in the room. Any port in
+    /// the server name is ignored.
+    pub fn server_matches(&self, server_name: &str) -> bool {
+        let host = strip_port(server_name);
+
+        if !self.allow_ip_literals && is_ip_literal(host) {
+            return false;
+        }
+
+        if self.deny.iter().any(|glob| glob_matches(glob, host)) {
+            return false;
+        }
+
+        self.allow.iter().any(|glob| glob_matches(glob, host))
+    }
+}
+
+/// Strips the port from a server name, handling IPv6 literals.
+fn strip_port(server_name: &str) -> &str {
+    if server_name.starts_with('[') {
+        if let Some(end) = server_name.find(']') {
+            return &server_name[..end + 1];
+        }
+        return server_name;
+    }
+
+    server_name.split(':').next().unwrap_or(server_name)
+}
+
+fn is_ip_literal(host: &str) -> bool {
+    host.starts_with('[') || host.parse::<Ipv4Addr>().is_ok()
+}
+
+/// Matches a glob where `*` matches zero or more characters and `?` matches
+/// exactly one.
+fn glob_matches(glob: &str, s: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+
+    let (mut g, mut i) = (0, 0);
+    // The position of the last `*` in the glob, and the position in the
+    // string it was matched against, so that we can backtrack.
+    let mut star = None;
+
+    while i < s.len() {
+        if g < glob.len() && (glob[g] == '?' || glob[g] == s[i]) {
+            g += 1;
+            i += 1;
+        } else if g < glob.len() && glob[g] == '*' {
+            star = Some((g, i));
+            g += 1;
+        } else if let Some((star_g, star_i)) = star {
+            g = star_g + 1;
+            i = star_i + 1;
+            star = Some((star_g, star_i + 1));
+        } else {
+            return false;
+        }
+    }
+
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+#[test]
+fn test_glob_matches() {
+    assert!(glob_matches("*", ""));
+    assert!(glob_matches("*.example.com", "matrix.example.com"));
+    assert!(!glob_matches("*.example.com", "example.com"));
+    assert!(glob_matches("ex?mple.c*m", "example.com"));
+    assert!(glob_matches("a*b*c", "aXbYbZc"));
+    assert!(!glob_matches("a*b*c", "aXbYbZ"));
+}
+
+#[test]
+fn test_server_matches() {
+    use serde_json;
+
+    let event: Event = serde_json::from_str(
+        r#"{"sender": "@a:a", "room_id": "!r:a", "event_id": "$1:a", "type": "m.room.server_acl",
+        "state_key": "", "prev_events": [], "depth": 1, "content": {"allow": ["*", 5],
+        "deny": ["evil.com", "*.evil.com"], "allow_ip_literals": false}}"#,
+    )
+    .unwrap();
+
+    let acl = ServerAcl::from_event(&event);
+    assert_eq!(acl.allow, vec!["*".to_string()]);
+
+    assert!(acl.server_matches("good.com"));
+    assert!(acl.server_matches("good.com:8448"));
+    assert!(!acl.server_matches("evil.com:8448"));
+    assert!(!acl.server_matches("matrix.evil.com"));
+    assert!(!acl.server_matches("1.2.3.4"));
+    assert!(!acl.server_matches("[::1]:8448"));
+
+    // An ACL without an allow list denies everyone.
+    assert!(!ServerAcl::default().server_matches("good.com"));
+}
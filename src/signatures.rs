@@ -0,0 +1,408 @@
+//! Verification of event signatures against a local store of server keys.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+
+use base64;
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+use failure::Error;
+use serde_json::{self, Value};
+
+use auth::{get_domain_from_id, Event};
+use canonical_json::encode_canonical_json;
+use redaction::redact_json;
+use room_version::{EventFormat, RoomVersion};
+
+/// A server's signing keys, in the format returned by the
+/// `/_matrix/key/v2/server` API.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerKeys {
+    pub server_name: String,
+    #[serde(default)]
+    pub verify_keys: HashMap<String, KeyObject>,
+    #[serde(default)]
+    pub old_verify_keys: HashMap<String, KeyObject>,
+    pub valid_until_ts: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct KeyObject {
+    pub key: String,
+    pub expired_ts: Option<u64>,
+}
+
+/// A key file may contain a single set of server keys, a list of them, or a
+/// `/_matrix/key/v2/query` response.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeyFile {
+    Query { server_keys: Vec<ServerKeys> },
+    Multiple(Vec<ServerKeys>),
+    Single(ServerKeys),
+}
+
+/// A verify key for a server, along with the period it's valid for.
+#[derive(Debug, Clone)]
+pub struct VerifyKey {
+    pub key: PublicKey,
+    /// When the key should no longer be trusted, for current keys.
+    pub valid_until_ts: Option<u64>,
+    /// When the key stopped being used, for old keys.
+    pub expired_ts: Option<u64>,
+}
+
+impl VerifyKey {
+    /// Whether the key can be used to verify an event sent at the given
+    /// time.
+    pub fn is_valid_at(&self, ts: u64) -> bool {
+        if let Some(expired_ts) = self.expired_ts {
+            return ts < expired_ts;
+        }
+
+        self.valid_until_ts.map(|v| ts <= v).unwrap_or(true)
+    }
+}
+
+/// A local store of server signing keys, keyed by server name and key ID.
+#[derive(Debug, Clone, Default)]
+pub struct KeyStore {
+    keys: HashMap<String, HashMap<String, VerifyKey>>,
+}
+
+impl KeyStore {
+    pub fn new() -> KeyStore {
+        KeyStore::default()
+    }
+
+    /// Load keys from either a JSON file or a directory of JSON files.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<KeyStore, Error> {
+        let mut store = KeyStore::new();
+
+        let path = path.as_ref();
+        if path.is_dir() {
+            for entry in fs::read_dir(path)? {
+                let entry_path = entry?.path();
+                if entry_path.extension().map(|e| e == "json").unwrap_or(false) {
+                    store.load_file(&entry_path)?;
+                }
+            }
+        } else {
+            store.load_file(path)?;
+        }
+
+        Ok(store)
+    }
+
+    fn load_file(&mut self, path: &Path) -> Result<(), Error> {
+        let f = BufReader::new(File::open(path)?);
+        let key_file: KeyFile = serde_json::from_reader(f)
+            .map_err(|e| format_err!("invalid key file {}: {}", path.display(), e))?;
+
+        let server_keys = match key_file {
+            KeyFile::Query { server_keys } => server_keys,
+            KeyFile::Multiple(server_keys) => server_keys,
+            KeyFile::Single(server_keys) => vec![server_keys],
+        };
+
+        for keys in server_keys {
+            self.add_server_keys(keys)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn add_server_keys(&mut self, server_keys: ServerKeys) -> Result<(), Error> {
+        let keys = self.keys.entry(server_keys.server_name).or_default();
+
+        for (key_id, key) in server_keys.verify_keys {
+            let verify_key = VerifyKey {
+                key: decode_public_key(&key.key)?,
+                valid_until_ts: server_keys.valid_until_ts,
+                expired_ts: None,
+            };
+            keys.insert(key_id, verify_key);
+        }
+
+        for (key_id, key) in server_keys.old_verify_keys {
+            let verify_key = VerifyKey {
+                key: decode_public_key(&key.key)?,
+                valid_until_ts: None,
+                expired_ts: key.expired_ts,
+            };
+            keys.insert(key_id, verify_key);
+        }
+
+        Ok(())
+    }
+
+    pub fn add_key(&mut self, server_name: &str, key_id: &str, key: VerifyKey) {
+        self.keys
+            .entry(server_name.to_string())
+            .or_default()
+            .insert(key_id.to_string(), key);
+    }
+
+    pub fn get_key(&self, server_name: &str, key_id: &str) -> Option<&VerifyKey> {
+        self.keys.get(server_name).and_then(|k| k.get(key_id))
+    }
+}
+
+/// Decodes unpadded base64, as used throughout Matrix. Padded and URL safe
+/// input is also accepted.
+pub fn decode_base64(s: &str) -> Result<Vec<u8>, Error> {
+    let config = if s.contains(&['-', '_'][..]) {
+        base64::URL_SAFE_NO_PAD
+    } else {
+        base64::STANDARD_NO_PAD
+    };
+
+    base64::decode_config(s.trim_end_matches('='), config)
+        .map_err(|_| format_err!("invalid base64"))
+}
+
+pub fn decode_public_key(s: &str) -> Result<PublicKey, Error> {
+    let bytes = decode_base64(s)?;
+    PublicKey::from_bytes(&bytes).map_err(|_| format_err!("invalid ed25519 key"))
+}
+
+/// Checks the signature on the JSON object from the given server and key ID
+/// against the given key.
+pub fn verify_json_signature(
+    json: &serde_json::Map<String, Value>,
+    server_name: &str,
+    key_id: &str,
+    key: &PublicKey,
+) -> Result<(), Error> {
+    let signature = json
+        .get("signatures")
+        .and_then(|s| s.get(server_name))
+        .and_then(|s| s.get(key_id))
+        .and_then(Value::as_str)
+        .ok_or_else(|| format_err!("not signed by {} with {}", server_name, key_id))?;
+
+    let signature = decode_base64(signature)
+        .ok()
+        .and_then(|b| Signature::from_bytes(&b).ok())
+        .ok_or_else(|| format_err!("invalid signature from {}", key_id))?;
+
+    let mut unsigned = json.clone();
+    unsigned.remove("signatures");
+    unsigned.remove("unsigned");
+    let message = encode_canonical_json(&Value::Object(unsigned))?;
+
+    key.verify(message.as_bytes(), &signature)
+        .map_err(|_| format_err!("bad signature from {}", server_name))
+}
+
+/// Checks that the JSON object has been signed by the given server with a
+/// key in the store that was valid at the given time.
+pub fn verify_signed_json(
+    json: &serde_json::Map<String, Value>,
+    server_name: &str,
+    ts: Option<u64>,
+    key_store: &KeyStore,
+) -> Result<(), Error> {
+    let signatures = json
+        .get("signatures")
+        .and_then(|s| s.get(server_name))
+        .and_then(Value::as_object)
+        .ok_or_else(|| format_err!("not signed by {}", server_name))?;
+
+    let mut found_key = false;
+
+    for key_id in signatures.keys() {
+        if !key_id.starts_with("ed25519:") {
+            continue;
+        }
+
+        let verify_key = if let Some(verify_key) = key_store.get_key(server_name, key_id) {
+            verify_key
+        } else {
+            continue;
+        };
+
+        if let Some(ts) = ts {
+            if !verify_key.is_valid_at(ts) {
+                continue;
+            }
+        }
+
+        found_key = true;
+
+        if verify_json_signature(json, server_name, key_id, &verify_key.key).is_ok() {
+            return Ok(());
+        }
+    }
+
+    if found_key {
+        bail!("bad signature from {}", server_name);
+    } else {
+        bail!("no known valid key for {}", server_name);
+    }
+}
+
+/// Checks the event has been signed by all the servers that need to have
+/// signed it.
+pub fn verify_event(
+    event: &Event,
+    room_version: &RoomVersion,
+    key_store: &KeyStore,
+) -> Result<(), Error> {
+    let json = event.to_json(room_version);
+    let json = json.as_object().expect("event is an object");
+    let redacted = redact_json(json, room_version);
+
+    let mut servers = vec![get_domain_from_id(&event.sender)?];
+
+    if room_version.event_format == EventFormat::V1 {
+        let event_id_domain = get_domain_from_id(&event.event_id)?;
+        if !servers.contains(&event_id_domain) {
+            servers.push(event_id_domain);
+        }
+    }
+
+    // Restricted joins must also be signed by the server of the user that
+    // authorised them.
+    if room_version.restricted_join_rule
+        && event.etype == "m.room.member"
+        && event.content.get("membership").and_then(Value::as_str) == Some("join")
+    {
+        let authorising_user = event
+            .content
+            .get("join_authorised_via_users_server")
+            .and_then(Value::as_str);
+        if let Some(authorising_user) = authorising_user {
+            let authorising_domain = get_domain_from_id(authorising_user)?;
+            if !servers.contains(&authorising_domain) {
+                servers.push(authorising_domain);
+            }
+        }
+    }
+
+    let ts = if room_version.enforce_key_validity {
+        Some(event.origin_server_ts())
+    } else {
+        None
+    };
+
+    for server_name in servers {
+        verify_signed_json(&redacted, server_name, ts, key_store)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_verify_event() {
+    use ed25519_dalek::{Keypair, SecretKey, Signer};
+
+    let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+    let public = PublicKey::from(&secret);
+    let keypair = Keypair { secret, public };
+
+    let mut key_store = KeyStore::new();
+    key_store.add_key(
+        "a",
+        "ed25519:1",
+        VerifyKey {
+            key: public,
+            valid_until_ts: None,
+            expired_ts: None,
+        },
+    );
+
+    let mut event: Event = serde_json::from_str(
+        r#"{"sender": "@a:a", "room_id": "!r:a", "event_id": "$1:a", "type": "m.room.message",
+        "prev_events": [], "auth_events": [], "content": {"body": "hi"}, "depth": 2,
+        "origin": "a", "origin_server_ts": 5}"#,
+    )
+    .unwrap();
+
+    assert!(verify_event(&event, &RoomVersion::V1, &key_store).is_err());
+
+    // Sign the event
+    let json = event.to_json(&RoomVersion::V1);
+    let redacted = redact_json(json.as_object().unwrap(), &RoomVersion::V1);
+    let signature = keypair.sign(
+        encode_canonical_json(&Value::Object(redacted))
+            .unwrap()
+            .as_bytes(),
+    );
+
+    let signature = base64::encode_config(&signature.to_bytes()[..], base64::STANDARD_NO_PAD);
+    let signatures = format!(r#"{{"a": {{"ed25519:1": "{}"}}}}"#, signature);
+    event.other.insert(
+        "signatures".into(),
+        serde_json::from_str(&signatures).unwrap(),
+    );
+
+    verify_event(&event, &RoomVersion::V1, &key_store).unwrap();
+
+    // Changing the content doesn't change the signature, as it's redacted...
+    event
+        .content
+        .insert("body".into(), Value::String("bye".into()));
+    verify_event(&event, &RoomVersion::V1, &key_store).unwrap();
+
+    // ... but changing the sender does.
+    event.sender = "@b:a".into();
+    assert!(verify_event(&event, &RoomVersion::V1, &key_store).is_err());
+}
+
+#[test]
+fn test_verify_restricted_join() {
+    use ed25519_dalek::{Keypair, SecretKey, Signer};
+
+    let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+    let public = PublicKey::from(&secret);
+    let keypair = Keypair { secret, public };
+
+    let mut key_store = KeyStore::new();
+    for server_name in &["b", "c"] {
+        key_store.add_key(
+            server_name,
+            "ed25519:1",
+            VerifyKey {
+                key: public,
+                valid_until_ts: None,
+                expired_ts: None,
+            },
+        );
+    }
+
+    let mut event: Event = serde_json::from_str(
+        r#"{"sender": "@b:b", "room_id": "!r:a", "type": "m.room.member", "state_key": "@b:b",
+        "prev_events": [], "auth_events": [], "depth": 2, "origin_server_ts": 5,
+        "content": {"membership": "join", "join_authorised_via_users_server": "@c:c"}}"#,
+    )
+    .unwrap();
+
+    let json = event.to_json(&RoomVersion::V10);
+    let redacted = redact_json(json.as_object().unwrap(), &RoomVersion::V10);
+    let signature = keypair.sign(
+        encode_canonical_json(&Value::Object(redacted))
+            .unwrap()
+            .as_bytes(),
+    );
+    let signature = base64::encode_config(&signature.to_bytes()[..], base64::STANDARD_NO_PAD);
+
+    // Only signed by the joining server.
+    let signatures = format!(r#"{{"b": {{"ed25519:1": "{}"}}}}"#, signature);
+    event.other.insert(
+        "signatures".into(),
+        serde_json::from_str(&signatures).unwrap(),
+    );
+    assert!(verify_event(&event, &RoomVersion::V10, &key_store).is_err());
+
+    let signatures = format!(
+        r#"{{"b": {{"ed25519:1": "{0}"}}, "c": {{"ed25519:1": "{0}"}}}}"#,
+        signature
+    );
+    event.other.insert(
+        "signatures".into(),
+        serde_json::from_str(&signatures).unwrap(),
+    );
+    verify_event(&event, &RoomVersion::V10, &key_store).unwrap();
+}
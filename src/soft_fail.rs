@@ -0,0 +1,116 @@
+//! Detection of events that servers would have soft-failed.
+//!
+//! Servers accept an event if it passes auth against the state before it,
+//! but soft-fail it (i.e. don't treat it as a forward extremity or send it to
+//! clients) if it doesn't pass auth against the current state of the room.
+
+use std::collections::HashSet;
+
+use auth::{self, AuthError};
+use auth_chain::get_auth_events_from_state;
+use intern::EventHandle;
+use persistent_state_map::PersistentStateMap;
+use room::{RoomDag, StateGroups};
+use room_version::RoomVersion;
+
+/// An event that passes auth against the state before it, but not against
+/// the current state of the room when it was received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SoftFailure {
+    pub event_id: EventHandle,
+    pub error: AuthError,
+}
+
+/// Finds the events that would have been soft-failed if they were received
+/// in the given order, using the current state as given by the resolved
+/// state of the forward extremities at the time.
+///
+/// As servers do, soft-failed events (and events that were rejected) don't
+/// become forward extremities.
+pub fn find_soft_failures(
+    dag: &RoomDag,
+    groups: &StateGroups,
+    ordered: &[EventHandle],
+    room_version: &RoomVersion,
+) -> Vec<SoftFailure> {
+    let mut failures = Vec::new();
+    let mut extremities: HashSet<EventHandle> = HashSet::new();
+    // The resolved state of the extremities, updated when they change.
+    let mut current_state = PersistentStateMap::new();
+
+    for eid in ordered {
+        // Events that fail auth against the state before them are rejected
+        // rather than soft-failed.
+        if groups.rejected.contains(eid) {
+            continue;
+        }
+
+        let event = &dag.event_map[eid];
+
+        let auth_events =
+            get_auth_events_from_state(event, &current_state, &dag.event_map, room_version);
+
+        if let Err(error) = auth::check(event, &auth_events, room_version) {
+            failures.push(SoftFailure {
+                event_id: *eid,
+                error,
+            });
+            continue;
+        }
+
+        for prev_event in &event.prev_events {
+            extremities.remove(&prev_event.handle());
+        }
+        extremities.insert(*eid);
+
+        // Only resolve when there are multiple extremities, otherwise the
+        // current state is just the state at the event.
+        current_state = if extremities.len() == 1 {
+            groups.get_state(*eid).unwrap_or_default()
+        } else {
+            groups.resolve_state_at(extremities.iter().cloned(), &dag.event_map, room_version)
+        };
+    }
+
+    failures
+}
+
+#[test]
+fn test_find_soft_failures() {
+    use std::io::Cursor;
+
+    use room::StateCalculator;
+
+    // Bob joins, and then is banned on one branch while sending a message on
+    // another. The message is received after the ban.
+    let lines = r#"
+{"event_id": "$create", "sender": "@a:a", "room_id": "!r:a", "type": "m.room.create", "state_key": "", "prev_events": [], "auth_events": [], "content": {"creator": "@a:a"}, "depth": 1}
+{"event_id": "$join_a", "sender": "@a:a", "room_id": "!r:a", "type": "m.room.member", "state_key": "@a:a", "prev_events": [["$create", {}]], "auth_events": [["$create", {}]], "content": {"membership": "join"}, "depth": 2}
+{"event_id": "$jr", "sender": "@a:a", "room_id": "!r:a", "type": "m.room.join_rules", "state_key": "", "prev_events": [["$join_a", {}]], "auth_events": [["$create", {}], ["$join_a", {}]], "content": {"join_rule": "public"}, "depth": 3}
+{"event_id": "$join_b", "sender": "@b:b", "room_id": "!r:a", "type": "m.room.member", "state_key": "@b:b", "prev_events": [["$jr", {}]], "auth_events": [["$create", {}], ["$jr", {}]], "content": {"membership": "join"}, "depth": 4}
+{"event_id": "$ban_b", "sender": "@a:a", "room_id": "!r:a", "type": "m.room.member", "state_key": "@b:b", "prev_events": [["$join_b", {}]], "auth_events": [["$create", {}], ["$join_a", {}], ["$join_b", {}]], "content": {"membership": "ban"}, "depth": 5}
+{"event_id": "$msg_b", "sender": "@b:b", "room_id": "!r:a", "type": "m.room.message", "prev_events": [["$join_b", {}]], "auth_events": [["$create", {}], ["$join_b", {}]], "content": {}, "depth": 5}
+{"event_id": "$msg_c", "sender": "@c:c", "room_id": "!r:a", "type": "m.room.message", "prev_events": [["$ban_b", {}]], "auth_events": [["$create", {}]], "content": {}, "depth": 6}
+"#;
+
+    let dag = RoomDag::from_reader(Cursor::new(lines.trim())).unwrap();
+    let ordered: Vec<EventHandle> = vec![
+        "$create", "$join_a", "$jr", "$join_b", "$ban_b", "$msg_b", "$msg_c",
+    ].into_iter()
+        .map(EventHandle::intern)
+        .collect();
+    let groups = StateCalculator::calculate(&dag, RoomVersion::V1, &ordered);
+
+    // Charlie was never in the room, so his message is rejected rather than
+    // soft-failed.
+    assert!(groups.rejected.contains(&EventHandle::intern("$msg_c")));
+
+    let failures = find_soft_failures(&dag, &groups, &ordered, &RoomVersion::V1);
+    assert_eq!(
+        failures,
+        vec![SoftFailure {
+            event_id: EventHandle::intern("$msg_b"),
+            error: AuthError::SenderNotInRoom,
+        }]
+    );
+}
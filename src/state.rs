@@ -4,50 +4,49 @@ use sha1::Sha1;
 use smallvec::SmallVec;
 
 use auth::{self, Event};
+use intern::EventHandle;
+use room_version::{RoomVersion, StateResolutionVersion};
 use state_map::{StateMap, WellKnownEmptyKeys};
 
-/// Resolves a list of states to a single state.
+pub mod v2;
+
+/// Resolves a list of states to a single state, using the algorithm for the
+/// given room version.
 pub fn resolve_state(
-    state_sets: Vec<&StateMap<String>>,
-    event_map: &HashMap<String, Event>,
-) -> StateMap<String> {
-    if state_sets.len() == 0 {
-        return StateMap::new();
+    state_sets: Vec<&StateMap<EventHandle>>,
+    event_map: &HashMap<EventHandle, Event>,
+    room_version: &RoomVersion,
+) -> StateMap<EventHandle> {
+    match room_version.state_res {
+        StateResolutionVersion::V1 => resolve_state_v1(state_sets, event_map, room_version),
+        StateResolutionVersion::V2 => v2::resolve_state(state_sets, event_map, room_version),
     }
+}
 
-    let mut unconflicted = state_sets[0].clone();
-    let mut conflicted: StateMap<SmallVec<[&auth::Event; 5]>> = StateMap::new();
-
-    for map in &state_sets[1..] {
-        'outer: for ((t, s), eid) in map.iter() {
-            if let Some(mut v) = conflicted.get_mut(t, s) {
-                for ev in v.iter() {
-                    if &ev.event_id == eid {
-                        continue 'outer;
-                    }
-                }
-                v.push(&event_map[eid]);
-                continue;
-            }
-            if let Some(eid_prev) = unconflicted.add_or_remove(t, s, eid) {
-                let mut v = conflicted.get_mut_or_default(t, s);
-                v.push(&event_map[eid]);
-                v.push(&event_map[&eid_prev]);
-            }
-        }
+/// Resolves a list of states to a single state using the original
+/// algorithm.
+pub fn resolve_state_v1(
+    state_sets: Vec<&StateMap<EventHandle>>,
+    event_map: &HashMap<EventHandle, Event>,
+    room_version: &RoomVersion,
+) -> StateMap<EventHandle> {
+    if state_sets.is_empty() {
+        return StateMap::new();
     }
 
+    let (unconflicted, conflicted) = separate(&state_sets, event_map);
+
     let mut auth_events_types = HashSet::new();
     for events in conflicted.values() {
         for event in events {
-            auth_events_types.extend(auth::auth_types_for_event(event).into_iter())
+            auth_events_types.extend(auth::auth_types_for_event(event, room_version))
         }
     }
 
     let mut auth_events = StateMap::new();
     for (t, s) in auth_events_types {
         if let Some(evid) = unconflicted.get(&t, &s) {
-            auth_events.insert(&t, &s, &event_map[evid as &str]);
+            auth_events.insert(&t, &s, &event_map[evid]);
         }
     }
 
@@ -58,80 +57,112 @@ pub fn resolve_state(
             (WellKnownEmptyKeys::PowerLevels.as_str(), ""),
             events.to_vec(),
             &auth_events,
+            room_version,
         );
 
-        resolved_state.insert_well_known(WellKnownEmptyKeys::PowerLevels, ev.event_id.clone());
+        resolved_state.insert_well_known(WellKnownEmptyKeys::PowerLevels, ev.handle());
         auth_events.insert_well_known(WellKnownEmptyKeys::PowerLevels, ev);
     }
 
     let join_auth_events = auth_events.clone();
     for (state_key, events) in conflicted.iter_join_rules() {
         let key = ("m.room.join_rules", state_key);
-        let ev = resolve_auth_events(key, events.to_vec(), &join_auth_events);
+        let ev = resolve_auth_events(key, events.to_vec(), &join_auth_events, room_version);
 
-        resolved_state.insert(key.0, key.1, ev.event_id.clone());
+        resolved_state.insert(key.0, key.1, ev.handle());
         auth_events.insert(key.0, key.1, ev);
     }
 
     let member_auth_events = auth_events.clone();
     for (user, events) in conflicted.iter_members() {
         let key = ("m.room.member", user);
-        let ev = resolve_auth_events(key, events.to_vec(), &member_auth_events);
+        let ev = resolve_auth_events(key, events.to_vec(), &member_auth_events, room_version);
 
-        resolved_state.insert(key.0, key.1, ev.event_id.clone());
+        resolved_state.insert(key.0, key.1, ev.handle());
         auth_events.insert(key.0, key.1, ev);
     }
 
     for (key, events) in conflicted.iter_non_members() {
         if !resolved_state.contains_key(key.0, key.1) {
-            let ev = resolve_normal_events(events.to_vec(), &auth_events);
+            let ev = resolve_normal_events(events.to_vec(), &auth_events, room_version);
 
-            resolved_state.insert(key.0, key.1, ev.event_id.clone());
+            resolved_state.insert(key.0, key.1, ev.handle());
         }
     }
 
     resolved_state
 }
 
+/// Splits the state sets into the unconflicted state and, for each conflicted
+/// key, the list of competing events.
+fn separate<'a>(
+    state_sets: &[&StateMap<EventHandle>],
+    event_map: &'a HashMap<EventHandle, Event>,
+) -> (StateMap<EventHandle>, StateMap<SmallVec<[&'a Event; 5]>>) {
+    let mut unconflicted = state_sets[0].clone();
+    let mut conflicted: StateMap<SmallVec<[&auth::Event; 5]>> = StateMap::new();
+
+    for map in &state_sets[1..] {
+        'outer: for ((t, s), eid) in map.iter() {
+            if let Some(v) = conflicted.get_mut(t, s) {
+                for ev in v.iter() {
+                    if ev.event_id == eid.as_str() {
+                        continue 'outer;
+                    }
+                }
+                v.push(&event_map[eid]);
+                continue;
+            }
+            if let Some(eid_prev) = unconflicted.add_or_remove(t, s, eid) {
+                let v = conflicted.get_mut_or_default(t, s);
+                v.push(&event_map[eid]);
+                v.push(&event_map[&eid_prev]);
+            }
+        }
+    }
+
+    (unconflicted, conflicted)
+}
+
 fn resolve_auth_events<'a>(
     key: (&str, &str),
     mut events: Vec<&'a auth::Event>,
     auth_events: &StateMap<&'a auth::Event>,
+    room_version: &RoomVersion,
 ) -> &'a auth::Event {
     order_events(&mut events);
     events.reverse();
 
     let mut new_auth_events = auth_events.clone();
 
-    let key = (&key.0 as &str, &key.1 as &str);
-
     let mut prev_event = &events[0];
     for event in &events[1..] {
         new_auth_events.insert(key.0, key.1, prev_event);
 
-        if auth::check(event, &new_auth_events).is_err() {
+        if auth::check(event, &new_auth_events, room_version).is_err() {
             return prev_event;
         }
 
         prev_event = event
     }
 
-    return prev_event;
+    prev_event
 }
 
 fn resolve_normal_events<'a>(
     mut events: Vec<&'a auth::Event>,
     auth_events: &StateMap<&'a auth::Event>,
+    room_version: &RoomVersion,
 ) -> &'a auth::Event {
     order_events(&mut events);
 
     for event in &events {
-        if auth::check(event, &auth_events).is_ok() {
+        if auth::check(event, auth_events, room_version).is_ok() {
             return event;
         }
     }
 
-    return events.last().unwrap();
+    events.last().unwrap()
 }
 
 fn order_events(events: &mut Vec<&auth::Event>) {
@@ -149,10 +180,14 @@ fn test_order_events() {
         etype: String::new(),
         state_key: None,
         prev_events: Vec::new(),
+        auth_events: None,
+        origin_server_ts: None,
         room_id: String::new(),
         redacts: None,
         sender: String::new(),
         content: serde_json::Map::new(),
+        other: serde_json::Map::new(),
+        cache: Default::default(),
     };
 
     let event2 = auth::Event {
@@ -162,10 +197,14 @@ fn test_order_events() {
         etype: String::new(),
         state_key: None,
         prev_events: Vec::new(),
+        auth_events: None,
+        origin_server_ts: None,
         room_id: String::new(),
         redacts: None,
         sender: String::new(),
         content: serde_json::Map::new(),
+        other: serde_json::Map::new(),
+        cache: Default::default(),
     };
 
     let event3 = auth::Event {
@@ -175,10 +214,14 @@ fn test_order_events() {
         etype: String::new(),
         state_key: None,
         prev_events: Vec::new(),
+        auth_events: None,
+        origin_server_ts: None,
         room_id: String::new(),
         redacts: None,
         sender: String::new(),
         content: serde_json::Map::new(),
+        other: serde_json::Map::new(),
+        cache: Default::default(),
     };
 
     let mut vec = vec![&event1, &event2, &event3];
@@ -0,0 +1,420 @@
+//! Version 2 of the state resolution algorithm, as used by room versions 2
+//! and later.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use serde_json::Value;
+
+use auth::{self, Event, EventReference};
+use auth_chain::{auth_chain_difference, get_auth_events};
+use intern::EventHandle;
+use room_version::RoomVersion;
+use state_map::{StateMap, WellKnownEmptyKeys};
+
+/// Resolves a list of states to a single state.
+pub fn resolve_state(
+    state_sets: Vec<&StateMap<EventHandle>>,
+    event_map: &HashMap<EventHandle, Event>,
+    room_version: &RoomVersion,
+) -> StateMap<EventHandle> {
+    if state_sets.is_empty() {
+        return StateMap::new();
+    }
+
+    let (unconflicted, conflicted) = separate(&state_sets);
+
+    if conflicted.is_empty() {
+        return unconflicted;
+    }
+
+    // The full conflicted set is the conflicted events plus the auth
+    // difference between the state sets.
+    let mut full_conflicted_set = conflicted;
+    full_conflicted_set.extend(auth_chain_difference(&state_sets, event_map));
+    full_conflicted_set.retain(|eid| event_map.contains_key(eid));
+
+    let power_events: Vec<EventHandle> = full_conflicted_set
+        .iter()
+        .filter(|eid| is_power_event(&event_map[*eid]))
+        .cloned()
+        .collect();
+
+    let sorted_power_events = reverse_topological_power_sort(
+        &power_events,
+        &full_conflicted_set,
+        event_map,
+        room_version,
+    );
+
+    let mut resolved_state = iterative_auth_checks(
+        &sorted_power_events,
+        &unconflicted,
+        event_map,
+        room_version,
+    );
+
+    // The remaining events are ordered by their position relative to the
+    // resolved power levels' mainline.
+    let sorted_power_set: HashSet<EventHandle> = sorted_power_events.iter().cloned().collect();
+    let leftover_events: Vec<EventHandle> = full_conflicted_set
+        .iter()
+        .filter(|eid| !sorted_power_set.contains(eid))
+        .cloned()
+        .collect();
+
+    let power_event = resolved_state
+        .get_well_known(WellKnownEmptyKeys::PowerLevels)
+        .cloned();
+
+    let sorted_leftover_events = mainline_sort(&leftover_events, power_event, event_map);
+
+    resolved_state = iterative_auth_checks(
+        &sorted_leftover_events,
+        &resolved_state,
+        event_map,
+        room_version,
+    );
+
+    // Finally, the unconflicted state takes precedence.
+    for ((t, s), eid) in unconflicted.iter() {
+        resolved_state.insert(t, s, *eid);
+    }
+
+    resolved_state
+}
+
+/// Splits the state sets into the unconflicted state and the conflicted
+/// events. Unlike v1, a key that is missing from some of the state sets is
+/// conflicted.
+fn separate(
+    state_sets: &[&StateMap<EventHandle>],
+) -> (StateMap<EventHandle>, HashSet<EventHandle>) {
+    let mut unconflicted = StateMap::new();
+    let mut conflicted = HashSet::new();
+
+    let mut seen = HashSet::new();
+    for map in state_sets {
+        for ((t, s), eid) in map.iter() {
+            if !seen.insert((t, s)) {
+                continue;
+            }
+
+            let values: Vec<Option<&EventHandle>> =
+                state_sets.iter().map(|m| m.get(t, s)).collect();
+
+            if values.iter().all(|v| *v == Some(eid)) {
+                unconflicted.insert(t, s, *eid);
+            } else {
+                conflicted.extend(values.into_iter().flatten().cloned());
+            }
+        }
+    }
+
+    (unconflicted, conflicted)
+}
+
+/// Power events are those that may remove the ability of another user to do
+/// something.
+fn is_power_event(event: &Event) -> bool {
+    let state_key = if let Some(ref state_key) = event.state_key {
+        state_key
+    } else {
+        return false;
+    };
+
+    match &event.etype as &str {
+        "m.room.power_levels" | "m.room.join_rules" | "m.room.create" => state_key.is_empty(),
+        "m.room.member" => {
+            let membership = event.content.get("membership").and_then(Value::as_str);
+
+            (membership == Some("leave") || membership == Some("ban")) && state_key != &event.sender
+        }
+        _ => false,
+    }
+}
+
+/// Returns the power levels event in the event's auth events.
+fn get_power_levels_auth_event<'a>(
+    event: &Event,
+    event_map: &'a HashMap<EventHandle, Event>,
+) -> Option<&'a Event> {
+    event
+        .auth_events()
+        .iter()
+        .filter_map(|a| event_map.get(&a.handle()))
+        .find(|ev| {
+            ev.etype == "m.room.power_levels"
+                && ev.state_key.as_ref().map(|s| s as &str) == Some("")
+        })
+}
+
+/// Sorts the events, and their auth chains within the full conflicted set,
+/// so that auth events come before the events they authorise. Ties are
+/// broken by the sender's power level, then timestamp, then event ID.
+fn reverse_topological_power_sort(
+    events: &[EventHandle],
+    full_conflicted_set: &HashSet<EventHandle>,
+    event_map: &HashMap<EventHandle, Event>,
+    room_version: &RoomVersion,
+) -> Vec<EventHandle> {
+    let mut graph: HashMap<EventHandle, HashSet<EventHandle>> = HashMap::new();
+
+    let mut stack = events.to_vec();
+    while let Some(eid) = stack.pop() {
+        if graph.contains_key(&eid) {
+            continue;
+        }
+
+        let auth_events: HashSet<EventHandle> = event_map[&eid]
+            .auth_events()
+            .iter()
+            .map(EventReference::handle)
+            .filter(|a| full_conflicted_set.contains(a))
+            .collect();
+
+        stack.extend(auth_events.iter().cloned());
+        graph.insert(eid, auth_events);
+    }
+
+    // Handles are ordered by when they were interned, so ties are broken by
+    // the event ID string.
+    let keys: HashMap<EventHandle, (i64, u64, &str)> = graph
+        .keys()
+        .map(|eid| {
+            let event = &event_map[eid];
+            let auth_events = get_auth_events(event, event_map);
+            let power_level =
+                auth::get_user_power_level(&event.sender, &auth_events, room_version);
+
+            (*eid, (-power_level, event.origin_server_ts(), eid.as_str()))
+        })
+        .collect();
+
+    lexicographical_topological_sort(&graph, |eid| keys[&eid])
+}
+
+/// Topologically sorts the graph, which maps events to the events they point
+/// to, such that events that point to nothing come first. Events that can
+/// be output at the same point are ordered by the given key.
+fn lexicographical_topological_sort<F, K>(
+    graph: &HashMap<EventHandle, HashSet<EventHandle>>,
+    key: F,
+) -> Vec<EventHandle>
+where
+    F: Fn(EventHandle) -> K,
+    K: Ord,
+{
+    let mut outdegree: HashMap<EventHandle, usize> = HashMap::new();
+    let mut reverse_graph: HashMap<EventHandle, Vec<EventHandle>> = HashMap::new();
+
+    let mut heap = BinaryHeap::new();
+
+    for (eid, edges) in graph {
+        outdegree.insert(*eid, edges.len());
+
+        for edge in edges {
+            reverse_graph.entry(*edge).or_default().push(*eid);
+        }
+
+        if edges.is_empty() {
+            heap.push(Reverse((key(*eid), *eid)));
+        }
+    }
+
+    let mut sorted = Vec::with_capacity(graph.len());
+
+    while let Some(Reverse((_, eid))) = heap.pop() {
+        sorted.push(eid);
+
+        for parent in reverse_graph.get(&eid).into_iter().flat_map(|v| v.iter()) {
+            let degree = outdegree.get_mut(parent).expect("event in graph");
+            *degree -= 1;
+
+            if *degree == 0 {
+                heap.push(Reverse((key(*parent), *parent)));
+            }
+        }
+    }
+
+    sorted
+}
+
+/// Sorts the events by their mainline depth, i.e. the position of the
+/// closest power levels event in the resolved power levels event's chain of
+/// power levels auth events. Ties are broken by timestamp then event ID.
+fn mainline_sort(
+    events: &[EventHandle],
+    power_event: Option<EventHandle>,
+    event_map: &HashMap<EventHandle, Event>,
+) -> Vec<EventHandle> {
+    let mut mainline = Vec::new();
+    let mut pl = power_event.and_then(|eid| event_map.get(&eid));
+    while let Some(ev) = pl {
+        mainline.push(ev.handle());
+        pl = get_power_levels_auth_event(ev, event_map);
+    }
+
+    let mainline_map: HashMap<EventHandle, usize> = mainline
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(idx, eid)| (*eid, idx + 1))
+        .collect();
+
+    let mut keyed: Vec<_> = events
+        .iter()
+        .map(|eid| {
+            let event = &event_map[eid];
+
+            let mut depth = 0;
+            let mut current = Some(event);
+            while let Some(ev) = current {
+                if let Some(d) = mainline_map.get(&ev.handle()) {
+                    depth = *d;
+                    break;
+                }
+
+                current = get_power_levels_auth_event(ev, event_map);
+            }
+
+            (depth, event.origin_server_ts(), eid.as_str(), *eid)
+        })
+        .collect();
+
+    keyed.sort();
+
+    keyed.into_iter().map(|(_, _, _, eid)| eid).collect()
+}
+
+/// Sequentially auth each event against the partially resolved state,
+/// updating the state with each event that passes.
+fn iterative_auth_checks(
+    events: &[EventHandle],
+    base_state: &StateMap<EventHandle>,
+    event_map: &HashMap<EventHandle, Event>,
+    room_version: &RoomVersion,
+) -> StateMap<EventHandle> {
+    let mut resolved_state = base_state.clone();
+
+    for eid in events {
+        let event = &event_map[eid];
+
+        let state_key = if let Some(ref state_key) = event.state_key {
+            state_key
+        } else {
+            continue;
+        };
+
+        let mut auth_events = get_auth_events(event, event_map);
+        for (t, s) in auth::auth_types_for_event(event, room_version) {
+            if let Some(ev) = resolved_state.get(&t, &s).and_then(|e| event_map.get(e)) {
+                auth_events.insert(&t, &s, ev);
+            }
+        }
+
+        if auth::check(event, &auth_events, room_version).is_ok() {
+            resolved_state.insert(&event.etype, state_key, *eid);
+        }
+    }
+
+    resolved_state
+}
+
+#[test]
+fn test_resolve_state() {
+    use serde_json;
+
+    let lines = r#"
+{"event_id": "$create", "sender": "@alice:a", "room_id": "!r:a", "type": "m.room.create", "state_key": "", "prev_events": [], "auth_events": [], "content": {"creator": "@alice:a"}, "depth": 1, "origin_server_ts": 1}
+{"event_id": "$join_a", "sender": "@alice:a", "room_id": "!r:a", "type": "m.room.member", "state_key": "@alice:a", "prev_events": [["$create", {}]], "auth_events": [["$create", {}]], "content": {"membership": "join"}, "depth": 2, "origin_server_ts": 2}
+{"event_id": "$pl", "sender": "@alice:a", "room_id": "!r:a", "type": "m.room.power_levels", "state_key": "", "prev_events": [["$join_a", {}]], "auth_events": [["$create", {}], ["$join_a", {}]], "content": {"users": {"@alice:a": 100, "@bob:a": 50}, "events": {"m.room.join_rules": 100}}, "depth": 3, "origin_server_ts": 3}
+{"event_id": "$jr", "sender": "@alice:a", "room_id": "!r:a", "type": "m.room.join_rules", "state_key": "", "prev_events": [["$pl", {}]], "auth_events": [["$create", {}], ["$join_a", {}], ["$pl", {}]], "content": {"join_rule": "public"}, "depth": 4, "origin_server_ts": 4}
+{"event_id": "$join_b", "sender": "@bob:a", "room_id": "!r:a", "type": "m.room.member", "state_key": "@bob:a", "prev_events": [["$jr", {}]], "auth_events": [["$create", {}], ["$jr", {}], ["$pl", {}]], "content": {"membership": "join"}, "depth": 5, "origin_server_ts": 5}
+{"event_id": "$topic1", "sender": "@alice:a", "room_id": "!r:a", "type": "m.room.topic", "state_key": "", "prev_events": [["$join_b", {}]], "auth_events": [["$create", {}], ["$join_a", {}], ["$pl", {}]], "content": {"topic": "1"}, "depth": 6, "origin_server_ts": 10}
+{"event_id": "$jr_bob", "sender": "@bob:a", "room_id": "!r:a", "type": "m.room.join_rules", "state_key": "", "prev_events": [["$topic1", {}]], "auth_events": [["$create", {}], ["$join_b", {}], ["$pl", {}]], "content": {"join_rule": "invite"}, "depth": 7, "origin_server_ts": 11}
+{"event_id": "$topic2", "sender": "@bob:a", "room_id": "!r:a", "type": "m.room.topic", "state_key": "", "prev_events": [["$join_b", {}]], "auth_events": [["$create", {}], ["$join_b", {}], ["$pl", {}]], "content": {"topic": "2"}, "depth": 6, "origin_server_ts": 12}
+"#;
+
+    let event_map: HashMap<EventHandle, Event> = lines
+        .trim()
+        .lines()
+        .map(|line| serde_json::from_str::<Event>(line).unwrap())
+        .map(|ev| (ev.handle(), ev))
+        .collect();
+
+    let base = [
+        (("m.room.create", ""), EventHandle::intern("$create")),
+        (("m.room.member", "@alice:a"), EventHandle::intern("$join_a")),
+        (("m.room.power_levels", ""), EventHandle::intern("$pl")),
+        (("m.room.member", "@bob:a"), EventHandle::intern("$join_b")),
+    ];
+
+    let mut state_a: StateMap<EventHandle> = base.iter().cloned().collect();
+    state_a.insert("m.room.topic", "", EventHandle::intern("$topic1"));
+    state_a.insert("m.room.join_rules", "", EventHandle::intern("$jr_bob"));
+
+    let mut state_b: StateMap<EventHandle> = base.iter().cloned().collect();
+    state_b.insert("m.room.topic", "", EventHandle::intern("$topic2"));
+    state_b.insert("m.room.join_rules", "", EventHandle::intern("$jr"));
+
+    let resolved = resolve_state(vec![&state_a, &state_b], &event_map, &RoomVersion::V2);
+
+    // Bob doesn't have the power to change the join rules...
+    assert_eq!(
+        resolved.get("m.room.join_rules", ""),
+        Some(&EventHandle::intern("$jr"))
+    );
+
+    // ... but can set the topic, and his is the later one.
+    assert_eq!(
+        resolved.get("m.room.topic", ""),
+        Some(&EventHandle::intern("$topic2"))
+    );
+
+    assert_eq!(
+        resolved.get("m.room.power_levels", ""),
+        Some(&EventHandle::intern("$pl"))
+    );
+}
+
+#[test]
+fn test_resolve_state_missing_key() {
+    use serde_json;
+
+    let lines = r#"
+{"event_id": "$m_create", "sender": "@alice:a", "room_id": "!r:a", "type": "m.room.create", "state_key": "", "prev_events": [], "auth_events": [], "content": {"creator": "@alice:a"}, "depth": 1, "origin_server_ts": 1}
+{"event_id": "$m_join_a", "sender": "@alice:a", "room_id": "!r:a", "type": "m.room.member", "state_key": "@alice:a", "prev_events": [["$m_create", {}]], "auth_events": [["$m_create", {}]], "content": {"membership": "join"}, "depth": 2, "origin_server_ts": 2}
+{"event_id": "$m_pl", "sender": "@alice:a", "room_id": "!r:a", "type": "m.room.power_levels", "state_key": "", "prev_events": [["$m_join_a", {}]], "auth_events": [["$m_create", {}], ["$m_join_a", {}]], "content": {"users": {"@alice:a": 100}}, "depth": 3, "origin_server_ts": 3}
+{"event_id": "$m_jr", "sender": "@alice:a", "room_id": "!r:a", "type": "m.room.join_rules", "state_key": "", "prev_events": [["$m_pl", {}]], "auth_events": [["$m_create", {}], ["$m_join_a", {}], ["$m_pl", {}]], "content": {"join_rule": "invite"}, "depth": 4, "origin_server_ts": 4}
+{"event_id": "$m_join_e", "sender": "@eve:e", "room_id": "!r:a", "type": "m.room.member", "state_key": "@eve:e", "prev_events": [["$m_jr", {}]], "auth_events": [["$m_create", {}], ["$m_jr", {}], ["$m_pl", {}]], "content": {"membership": "join"}, "depth": 5, "origin_server_ts": 5}
+"#;
+
+    let event_map: HashMap<EventHandle, Event> = lines
+        .trim()
+        .lines()
+        .map(|line| serde_json::from_str::<Event>(line).unwrap())
+        .map(|ev| (ev.handle(), ev))
+        .collect();
+
+    let base = [
+        (("m.room.create", ""), EventHandle::intern("$m_create")),
+        (("m.room.member", "@alice:a"), EventHandle::intern("$m_join_a")),
+        (("m.room.power_levels", ""), EventHandle::intern("$m_pl")),
+        (("m.room.join_rules", ""), EventHandle::intern("$m_jr")),
+    ];
+
+    let state_a: StateMap<EventHandle> = base.iter().cloned().collect();
+    let mut state_b: StateMap<EventHandle> = base.iter().cloned().collect();
+    state_b.insert("m.room.member", "@eve:e", EventHandle::intern("$m_join_e"));
+
+    let resolved = resolve_state(vec![&state_a, &state_b], &event_map, &RoomVersion::V2);
+
+    // Eve's join is only on one side, so it must pass the auth checks, which
+    // it can't as the room is invite only.
+    assert_eq!(resolved.get("m.room.member", "@eve:e"), None);
+    assert_eq!(
+        resolved.get("m.room.join_rules", ""),
+        Some(&EventHandle::intern("$m_jr"))
+    );
+}
@@ -0,0 +1,253 @@
+//! Delta encoded storage of state groups, as Synapse does it.
+//!
+//! Each group is stored either as a full snapshot of the state, or as the
+//! group it was derived from plus the entries that changed, cf. Synapse's
+//! `state_groups_state` and `state_group_edges` tables. Lookups rebuild the
+//! state by applying the deltas to the nearest snapshot, so the length of
+//! delta chains is capped. The full states of the most recently inserted
+//! groups are cached, as those are the ones that are looked up the most when
+//! calculating state.
+
+use std::collections::{HashMap, VecDeque};
+
+use heapsize::HeapSizeOf;
+
+use intern::EventHandle;
+use persistent_state_map::PersistentStateMap;
+use state_map::StateMap;
+
+/// The maximum delta chain length that Synapse uses.
+pub const DEFAULT_MAX_DELTA_CHAIN: usize = 100;
+
+/// The number of recently inserted groups to keep the full state of.
+pub const DEFAULT_CACHE_SIZE: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub enum StoredGroup {
+    Snapshot(StateMap<EventHandle>),
+    Delta {
+        prev_group: i32,
+        /// The entries added or changed since the previous group.
+        delta: StateMap<EventHandle>,
+        /// The number of deltas to apply to the snapshot, including this
+        /// one.
+        chain_length: usize,
+    },
+}
+
+impl HeapSizeOf for StoredGroup {
+    fn heap_size_of_children(&self) -> usize {
+        match *self {
+            StoredGroup::Snapshot(ref state) => state.heap_size_of_children(),
+            StoredGroup::Delta { ref delta, .. } => delta.heap_size_of_children(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct StateGroupStore {
+    groups: HashMap<i32, StoredGroup>,
+    max_delta_chain: usize,
+    cache: HashMap<i32, PersistentStateMap<EventHandle>>,
+    /// The cached groups, oldest first.
+    cache_order: VecDeque<i32>,
+    cache_size: usize,
+}
+
+impl StateGroupStore {
+    pub fn new(max_delta_chain: usize) -> StateGroupStore {
+        StateGroupStore {
+            groups: HashMap::new(),
+            max_delta_chain,
+            cache: HashMap::new(),
+            cache_order: VecDeque::new(),
+            cache_size: DEFAULT_CACHE_SIZE,
+        }
+    }
+
+    /// Sets the maximum delta chain length for groups stored from now on.
+    pub fn set_max_delta_chain(&mut self, max_delta_chain: usize) {
+        self.max_delta_chain = max_delta_chain;
+    }
+
+    /// Sets how many recently inserted groups to keep the full state of.
+    pub fn set_cache_size(&mut self, cache_size: usize) {
+        self.cache_size = cache_size;
+        self.evict();
+    }
+
+    /// Stores the state of the group, as a delta against the state of
+    /// whichever of the previous groups gives the smallest delta. It's stored
+    /// as a snapshot if there are no previous groups, or the deltas would
+    /// make the chain too long or are missing entries from the previous
+    /// group, which deltas can't express.
+    pub fn insert(&mut self, sg: i32, prev_groups: &[i32], state: PersistentStateMap<EventHandle>) {
+        let stored = prev_groups
+            .iter()
+            .filter_map(|&prev_group| self.delta_against(prev_group, &state))
+            .min_by_key(|(delta, _)| delta.values().count())
+            .map(|(delta, prev_group)| StoredGroup::Delta {
+                prev_group,
+                delta,
+                chain_length: self.chain_length(prev_group).unwrap_or(0) + 1,
+            })
+            .unwrap_or_else(|| StoredGroup::Snapshot(state.to_state_map()));
+
+        self.groups.insert(sg, stored);
+
+        self.cache.insert(sg, state);
+        self.cache_order.push_back(sg);
+        self.evict();
+    }
+
+    fn delta_against(
+        &self,
+        prev_group: i32,
+        state: &PersistentStateMap<EventHandle>,
+    ) -> Option<(StateMap<EventHandle>, i32)> {
+        if self.chain_length(prev_group)? + 1 > self.max_delta_chain {
+            return None;
+        }
+
+        let diff = self.get_state(prev_group)?.diff(state);
+        if !diff.removed.is_empty() {
+            return None;
+        }
+
+        let changed = diff.changed.into_iter().map(|(key, _, new)| (key, new));
+        let delta = diff.added.into_iter().chain(changed).collect();
+
+        Some((delta, prev_group))
+    }
+
+    fn evict(&mut self) {
+        while self.cache_order.len() > self.cache_size {
+            if let Some(sg) = self.cache_order.pop_front() {
+                self.cache.remove(&sg);
+            }
+        }
+    }
+
+    pub fn get(&self, sg: i32) -> Option<&StoredGroup> {
+        self.groups.get(&sg)
+    }
+
+    /// The number of deltas that need applying to get the group's state.
+    pub fn chain_length(&self, sg: i32) -> Option<usize> {
+        match *self.groups.get(&sg)? {
+            StoredGroup::Snapshot(_) => Some(0),
+            StoredGroup::Delta { chain_length, .. } => Some(chain_length),
+        }
+    }
+
+    /// Gets the state of the group, rebuilding it from the nearest cached
+    /// or snapshot state and the deltas after it if it isn't cached.
+    pub fn get_state(&self, sg: i32) -> Option<PersistentStateMap<EventHandle>> {
+        let mut deltas = Vec::new();
+
+        let mut current = sg;
+        let mut state = loop {
+            if let Some(state) = self.cache.get(&current) {
+                break state.clone();
+            }
+
+            match *self.groups.get(&current)? {
+                StoredGroup::Snapshot(ref state) => {
+                    break PersistentStateMap::from_state_map(state)
+                }
+                StoredGroup::Delta {
+                    prev_group,
+                    ref delta,
+                    ..
+                } => {
+                    deltas.push(delta);
+                    current = prev_group;
+                }
+            }
+        };
+
+        for delta in deltas.iter().rev() {
+            for ((t, s), eid) in delta.iter() {
+                state.insert(t, s, *eid);
+            }
+        }
+
+        Some(state)
+    }
+
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+}
+
+impl Default for StateGroupStore {
+    fn default() -> StateGroupStore {
+        StateGroupStore::new(DEFAULT_MAX_DELTA_CHAIN)
+    }
+}
+
+/// The size of the stored groups. The cached states share most of their
+/// memory with each other, so aren't counted.
+impl HeapSizeOf for StateGroupStore {
+    fn heap_size_of_children(&self) -> usize {
+        self.groups.heap_size_of_children()
+    }
+}
+
+#[test]
+fn test_state_group_store() {
+    let eid = EventHandle::intern;
+
+    let mut state = PersistentStateMap::new();
+    state.insert("m.room.create", "", eid("$1:a"));
+
+    let mut store = StateGroupStore::new(2);
+    store.set_cache_size(0);
+    store.insert(1, &[], state.clone());
+
+    for sg in 2..6 {
+        state.insert("m.room.member", &format!("@{}:a", sg), eid("$2:a"));
+        store.insert(sg, &[sg - 1], state.clone());
+    }
+
+    let chains: Vec<_> = (1..6).map(|sg| store.chain_length(sg).unwrap()).collect();
+    assert_eq!(chains, vec![0, 1, 2, 0, 1]);
+
+    match *store.get(5).unwrap() {
+        StoredGroup::Delta { ref delta, .. } => assert_eq!(delta.values().count(), 1),
+        _ => panic!("expected a delta"),
+    }
+
+    let rebuilt = store.get_state(3).unwrap();
+    assert_eq!(rebuilt.len(), 3);
+    assert_eq!(rebuilt.get("m.room.member", "@3:a"), Some(&eid("$2:a")));
+    assert_eq!(rebuilt.get("m.room.member", "@4:a"), None);
+
+    // Removing an entry can't be expressed as a delta.
+    let mut smaller = state.clone();
+    smaller.remove("m.room.member", "@5:a");
+    store.insert(6, &[5], smaller);
+    assert_eq!(store.chain_length(6), Some(0));
+    assert!(store.get_state(7).is_none());
+
+    // Merges are stored against the previous group with the smallest delta.
+    state.insert("m.room.topic", "", eid("$3:a"));
+    store.insert(7, &[6, 5], state.clone());
+    match *store.get(7).unwrap() {
+        StoredGroup::Delta {
+            prev_group,
+            ref delta,
+            chain_length,
+        } => {
+            assert_eq!(prev_group, 5);
+            assert_eq!(delta.values().count(), 1);
+            assert_eq!(chain_length, 2);
+        }
+        _ => panic!("expected a delta"),
+    }
+    assert!(store.get_state(7).unwrap().diff(&state).is_empty());
+}
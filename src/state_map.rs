@@ -1,7 +1,13 @@
-use std::borrow::Borrow;
-use std::collections::{hash_map, HashMap};
-use std::fmt::Debug;
+use std::collections::{hash_map, BTreeMap, HashMap};
+use std::fmt::{self, Debug};
 use std::iter::FromIterator;
+use std::marker::PhantomData;
+
+use heapsize::HeapSizeOf;
+use serde::de::{MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use intern::StateKeyHandle;
 
 const TYPE_CREATE: &str = "m.room.create";
 const TYPE_POWER_LEVELS: &str = "m.room.power_levels";
@@ -21,7 +27,7 @@ const TYPE_THIRD_PARTY_INVITE: &str = "m.room.third_party_invite";
 
 /// List of event types that are commonly used for state with empty state
 /// keys.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, HeapSizeOf)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum WellKnownEmptyKeys {
     Create,
     PowerLevels,
@@ -53,6 +59,7 @@ impl WellKnownEmptyKeys {
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(t: &str) -> Option<WellKnownEmptyKeys> {
         match t {
             TYPE_CREATE => Some(WellKnownEmptyKeys::Create),
@@ -71,14 +78,22 @@ impl WellKnownEmptyKeys {
     }
 }
 
+impl HeapSizeOf for WellKnownEmptyKeys {
+    fn heap_size_of_children(&self) -> usize {
+        0
+    }
+}
+
 /// A specialised container for storing state mapping.
-#[derive(Debug, Clone, HeapSizeOf)]
+///
+/// State keys are stored as interned handles.
+#[derive(Debug, Clone)]
 pub struct StateMap<E: Debug + Clone> {
     well_known: HashMap<WellKnownEmptyKeys, E>,
-    membership: HashMap<String, E>,
-    aliases: HashMap<String, E>,
-    invites: HashMap<String, E>,
-    others: HashMap<String, HashMap<String, E>>,
+    membership: HashMap<StateKeyHandle, E>,
+    aliases: HashMap<StateKeyHandle, E>,
+    invites: HashMap<StateKeyHandle, E>,
+    others: HashMap<String, HashMap<StateKeyHandle, E>>,
 }
 
 impl<E> StateMap<E>
@@ -100,46 +115,50 @@ where
     }
 
     pub fn get_aliases(&self, server: &str) -> Option<&E> {
-        self.aliases.get(server)
+        StateKeyHandle::get(server).and_then(|k| self.aliases.get(&k))
     }
 
     pub fn get_membership(&self, server: &str) -> Option<&E> {
-        self.membership.get(server)
+        StateKeyHandle::get(server).and_then(|k| self.membership.get(&k))
     }
 
     pub fn get_third_party_invites(&self, token: &str) -> Option<&E> {
-        self.invites.get(token)
+        StateKeyHandle::get(token).and_then(|k| self.invites.get(&k))
     }
 
     pub fn get(&self, t: &str, s: &str) -> Option<&E> {
-        if s == "" {
+        if s.is_empty() {
             if let Some(key) = WellKnownEmptyKeys::from_str(t) {
                 return self.get_well_known(key);
             }
         }
 
-        match (t.borrow(), s.borrow()) {
+        match (t, s) {
             (TYPE_MEMBERSHIP, user) => self.get_membership(user),
             (TYPE_ALIASES, server) => self.get_aliases(server),
             (TYPE_THIRD_PARTY_INVITE, token) => self.get_third_party_invites(token),
 
-            (t, s) => self.others.get(t).and_then(|m| m.get(s)),
+            (t, s) => self.others
+                .get(t)
+                .and_then(|m| StateKeyHandle::get(s).and_then(|k| m.get(&k))),
         }
     }
 
     pub fn get_mut(&mut self, t: &str, s: &str) -> Option<&mut E> {
-        if s == "" {
+        if s.is_empty() {
             if let Some(key) = WellKnownEmptyKeys::from_str(t) {
                 return self.well_known.get_mut(&key);
             }
         }
 
-        match (t.borrow(), s.borrow()) {
-            (TYPE_MEMBERSHIP, user) => self.membership.get_mut(user),
-            (TYPE_ALIASES, server) => self.aliases.get_mut(server),
-            (TYPE_THIRD_PARTY_INVITE, token) => self.invites.get_mut(token),
+        let s = StateKeyHandle::get(s)?;
 
-            (t, s) => self.others.get_mut(t).and_then(|m| m.get_mut(s)),
+        match t {
+            TYPE_MEMBERSHIP => self.membership.get_mut(&s),
+            TYPE_ALIASES => self.aliases.get_mut(&s),
+            TYPE_THIRD_PARTY_INVITE => self.invites.get_mut(&s),
+
+            t => self.others.get_mut(t).and_then(|m| m.get_mut(&s)),
         }
     }
 
@@ -148,22 +167,24 @@ where
     }
 
     pub fn insert(&mut self, t: &str, s: &str, value: E) {
-        if s == "" {
+        if s.is_empty() {
             if let Some(key) = WellKnownEmptyKeys::from_str(t) {
                 self.well_known.insert(key, value);
                 return;
             }
         }
 
-        match (t, s) {
-            (TYPE_MEMBERSHIP, user) => self.membership.insert(user.into(), value),
-            (TYPE_ALIASES, server) => self.aliases.insert(server.into(), value),
-            (TYPE_THIRD_PARTY_INVITE, token) => self.invites.insert(token.into(), value),
+        let s = StateKeyHandle::intern(s);
 
-            (t, s) => self.others
+        match t {
+            TYPE_MEMBERSHIP => self.membership.insert(s, value),
+            TYPE_ALIASES => self.aliases.insert(s, value),
+            TYPE_THIRD_PARTY_INVITE => self.invites.insert(s, value),
+
+            t => self.others
                 .entry(t.into())
-                .or_insert_with(HashMap::new)
-                .insert(s.into(), value),
+                .or_default()
+                .insert(s, value),
         };
     }
 
@@ -171,20 +192,38 @@ where
         self.get(t, s).is_some()
     }
 
+    pub fn remove(&mut self, t: &str, s: &str) -> Option<E> {
+        if s.is_empty() {
+            if let Some(key) = WellKnownEmptyKeys::from_str(t) {
+                return self.well_known.remove(&key);
+            }
+        }
+
+        let s = StateKeyHandle::get(s)?;
+
+        match t {
+            TYPE_MEMBERSHIP => self.membership.remove(&s),
+            TYPE_ALIASES => self.aliases.remove(&s),
+            TYPE_THIRD_PARTY_INVITE => self.invites.remove(&s),
+
+            t => self.others.get_mut(t).and_then(|m| m.remove(&s)),
+        }
+    }
+
     pub fn keys(&self) -> impl Iterator<Item = (&str, &str)> {
         let w = self.well_known.keys().map(|k| (k.as_str(), ""));
 
-        let m = self.membership.keys().map(|u| (TYPE_MEMBERSHIP, u as &str));
+        let m = self.membership.keys().map(|u| (TYPE_MEMBERSHIP, u.as_str()));
 
-        let a = self.aliases.keys().map(|s| (TYPE_ALIASES, s as &str));
+        let a = self.aliases.keys().map(|s| (TYPE_ALIASES, s.as_str()));
 
         let i = self.invites
             .keys()
-            .map(|t| (TYPE_THIRD_PARTY_INVITE, t as &str));
+            .map(|t| (TYPE_THIRD_PARTY_INVITE, t.as_str()));
 
         let o = self.others
             .iter()
-            .flat_map(|(t, h)| h.keys().map(move |s| (t as &str, s as &str)));
+            .flat_map(|(t, h)| h.keys().map(move |s| (t as &str, s.as_str())));
 
         w.chain(m).chain(a).chain(i).chain(o)
     }
@@ -194,19 +233,19 @@ where
 
         let m = self.membership
             .iter()
-            .map(|(u, e)| ((TYPE_MEMBERSHIP, u as &str), e));
+            .map(|(u, e)| ((TYPE_MEMBERSHIP, u.as_str()), e));
 
         let a = self.aliases
             .iter()
-            .map(|(s, e)| ((TYPE_ALIASES, s as &str), e));
+            .map(|(s, e)| ((TYPE_ALIASES, s.as_str()), e));
 
         let i = self.invites
             .iter()
-            .map(|(t, e)| ((TYPE_THIRD_PARTY_INVITE, t as &str), e));
+            .map(|(t, e)| ((TYPE_THIRD_PARTY_INVITE, t.as_str()), e));
 
         let o = self.others
             .iter()
-            .flat_map(|(t, h)| h.iter().map(move |(s, e)| ((t as &str, s as &str), e)));
+            .flat_map(|(t, h)| h.iter().map(move |(s, e)| ((t as &str, s.as_str()), e)));
 
         w.chain(m).chain(a).chain(i).chain(o)
     }
@@ -226,7 +265,7 @@ where
     }
 
     pub fn iter_members(&self) -> impl Iterator<Item = (&str, &E)> {
-        self.membership.iter().map(|(u, e)| (u as &str, e))
+        self.membership.iter().map(|(u, e)| (u.as_str(), e))
     }
 
     pub fn iter_join_rules(&self) -> impl Iterator<Item = (&str, &E)> {
@@ -238,7 +277,7 @@ where
         let o = self.others
             .get(TYPE_JOIN_RULES)
             .into_iter()
-            .flat_map(|h| h.iter().map(move |(s, e)| (s as &str, e)));
+            .flat_map(|h| h.iter().map(move |(s, e)| (s.as_str(), e)));
 
         i.chain(o)
     }
@@ -248,45 +287,69 @@ where
 
         let a = self.aliases
             .iter()
-            .map(|(s, e)| ((TYPE_ALIASES, s as &str), e));
+            .map(|(s, e)| ((TYPE_ALIASES, s.as_str()), e));
 
         let i = self.invites
             .iter()
-            .map(|(t, e)| ((TYPE_THIRD_PARTY_INVITE, t as &str), e));
+            .map(|(t, e)| ((TYPE_THIRD_PARTY_INVITE, t.as_str()), e));
 
         let o = self.others
             .iter()
-            .flat_map(|(t, h)| h.iter().map(move |(s, e)| ((t as &str, s as &str), e)));
+            .flat_map(|(t, h)| h.iter().map(move |(s, e)| ((t as &str, s.as_str()), e)));
 
         w.chain(a).chain(i).chain(o)
     }
 }
 
+impl<E> Default for StateMap<E>
+where
+    E: Debug + Clone,
+{
+    fn default() -> StateMap<E> {
+        StateMap::new()
+    }
+}
+
+impl<E> HeapSizeOf for StateMap<E>
+where
+    E: Debug + Clone + HeapSizeOf,
+{
+    fn heap_size_of_children(&self) -> usize {
+        self.well_known.heap_size_of_children()
+            + self.membership.heap_size_of_children()
+            + self.aliases.heap_size_of_children()
+            + self.invites.heap_size_of_children()
+            + self.others.heap_size_of_children()
+    }
+}
+
 impl<E> StateMap<E>
 where
     E: Debug + Clone + Default,
 {
     pub fn get_mut_or_default(&mut self, t: &str, s: &str) -> &mut E {
-        if s == "" {
+        if s.is_empty() {
             if let Some(key) = WellKnownEmptyKeys::from_str(t) {
-                return self.well_known.entry(key).or_insert_with(E::default);
+                return self.well_known.entry(key).or_default();
             }
         }
 
-        if let Some(entry) = match (t, s) {
-            (TYPE_MEMBERSHIP, user) => Some(self.membership.entry(user.into())),
-            (TYPE_ALIASES, server) => Some(self.aliases.entry(server.into())),
-            (TYPE_THIRD_PARTY_INVITE, token) => Some(self.invites.entry(token.into())),
+        let s = StateKeyHandle::intern(s);
+
+        if let Some(entry) = match t {
+            TYPE_MEMBERSHIP => Some(self.membership.entry(s)),
+            TYPE_ALIASES => Some(self.aliases.entry(s)),
+            TYPE_THIRD_PARTY_INVITE => Some(self.invites.entry(s)),
 
             _ => None,
         } {
-            entry.or_insert_with(E::default)
+            entry.or_default()
         } else {
             self.others
                 .entry(t.into())
-                .or_insert_with(HashMap::new)
-                .entry(s.into())
-                .or_insert_with(E::default)
+                .or_default()
+                .entry(s)
+                .or_default()
         }
     }
 }
@@ -295,74 +358,143 @@ impl<E> StateMap<E>
 where
     E: Debug + Clone + PartialEq,
 {
+    /// Returns the changes needed to turn this state into `other`.
+    pub fn diff(&self, other: &StateMap<E>) -> StateDelta<E> {
+        let mut delta = StateDelta::default();
+
+        for ((t, s), e) in self.iter() {
+            let key = (t.to_string(), s.to_string());
+            match other.get(t, s) {
+                None => delta.removed.push((key, e.clone())),
+                Some(o) if o != e => delta.changed.push((key, e.clone(), o.clone())),
+                Some(_) => {}
+            }
+        }
+
+        for ((t, s), e) in other.iter() {
+            if !self.contains_key(t, s) {
+                delta.added.push(((t.to_string(), s.to_string()), e.clone()));
+            }
+        }
+
+        delta.added.sort_by(|a, b| a.0.cmp(&b.0));
+        delta.removed.sort_by(|a, b| a.0.cmp(&b.0));
+        delta.changed.sort_by(|a, b| a.0.cmp(&b.0));
+
+        delta
+    }
+
+    /// Applies the changes from `diff`.
+    pub fn apply(&mut self, delta: &StateDelta<E>) {
+        for &((ref t, ref s), _) in &delta.removed {
+            self.remove(t, s);
+        }
+
+        for &((ref t, ref s), ref e) in &delta.added {
+            self.insert(t, s, e.clone());
+        }
+
+        for &((ref t, ref s), _, ref new) in &delta.changed {
+            self.insert(t, s, new.clone());
+        }
+    }
+
     pub fn add_or_remove(&mut self, t: &str, s: &str, value: &E) -> Option<E> {
-        if s == "" {
+        if s.is_empty() {
             if let Some(key) = WellKnownEmptyKeys::from_str(t) {
-                match self.well_known.entry(key) {
+                return match self.well_known.entry(key) {
                     hash_map::Entry::Occupied(o) => {
                         if o.get() != value {
-                            return Some(o.remove());
+                            Some(o.remove())
                         } else {
-                            return None;
+                            None
                         }
                     }
                     hash_map::Entry::Vacant(v) => {
                         v.insert(value.clone());
-                        return None;
+                        None
                     }
-                }
+                };
             }
         }
 
-        if let Some(entry) = match (t, s) {
-            (TYPE_MEMBERSHIP, user) => Some(self.membership.entry(user.into())),
-            (TYPE_ALIASES, server) => Some(self.aliases.entry(server.into())),
-            (TYPE_THIRD_PARTY_INVITE, token) => Some(self.invites.entry(token.into())),
+        let s = StateKeyHandle::intern(s);
+
+        if let Some(entry) = match t {
+            TYPE_MEMBERSHIP => Some(self.membership.entry(s)),
+            TYPE_ALIASES => Some(self.aliases.entry(s)),
+            TYPE_THIRD_PARTY_INVITE => Some(self.invites.entry(s)),
 
             _ => None,
         } {
             match entry {
                 hash_map::Entry::Occupied(o) => {
                     if o.get() != value {
-                        return Some(o.remove());
+                        Some(o.remove())
                     } else {
-                        return None;
+                        None
                     }
                 }
                 hash_map::Entry::Vacant(v) => {
                     v.insert(value.clone());
-                    return None;
+                    None
                 }
             }
         } else {
             match self.others.entry(t.into()) {
-                hash_map::Entry::Occupied(mut o) => match o.get_mut().entry(s.into()) {
+                hash_map::Entry::Occupied(mut o) => match o.get_mut().entry(s) {
                     hash_map::Entry::Occupied(o) => {
                         if o.get() != value {
-                            return Some(o.remove());
+                            Some(o.remove())
                         } else {
-                            return None;
+                            None
                         }
                     }
                     hash_map::Entry::Vacant(v) => {
                         v.insert(value.clone());
-                        return None;
+                        None
                     }
                 },
                 hash_map::Entry::Vacant(v) => {
-                    v.insert(HashMap::new()).insert(s.into(), value.clone());
-                    return None;
+                    v.insert(HashMap::new()).insert(s, value.clone());
+                    None
                 }
             }
         }
     }
 }
 
+/// The differences between two states, keyed by `(type, state_key)` and
+/// sorted by key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateDelta<E> {
+    pub added: Vec<((String, String), E)>,
+    pub removed: Vec<((String, String), E)>,
+    /// The old and new values of entries in both states.
+    pub changed: Vec<((String, String), E, E)>,
+}
+
+impl<E> StateDelta<E> {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl<E> Default for StateDelta<E> {
+    fn default() -> StateDelta<E> {
+        StateDelta {
+            added: Vec::new(),
+            removed: Vec::new(),
+            changed: Vec::new(),
+        }
+    }
+}
+
 impl<E> FromIterator<((String, String), E)> for StateMap<E>
 where
     E: Debug + Clone,
 {
-    fn from_iter<T: IntoIterator<Item = (((String, String), E))>>(iter: T) -> StateMap<E> {
+    fn from_iter<T: IntoIterator<Item = ((String, String), E)>>(iter: T) -> StateMap<E> {
         let mut state_map = StateMap::new();
 
         for ((t, s), e) in iter {
@@ -377,17 +509,78 @@ impl<'a, E> FromIterator<((&'a str, &'a str), E)> for StateMap<E>
 where
     E: Debug + Clone,
 {
-    fn from_iter<T: IntoIterator<Item = (((&'a str, &'a str), E))>>(iter: T) -> StateMap<E> {
+    fn from_iter<T: IntoIterator<Item = ((&'a str, &'a str), E)>>(iter: T) -> StateMap<E> {
         let mut state_map = StateMap::new();
 
         for ((t, s), e) in iter {
-            state_map.insert(&t, &s, e);
+            state_map.insert(t, s, e);
         }
 
         state_map
     }
 }
 
+/// Serializes as `{type: {state_key: value}}`, sorted by type and state key.
+impl<E> Serialize for StateMap<E>
+where
+    E: Debug + Clone + Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut by_type: BTreeMap<&str, BTreeMap<&str, &E>> = BTreeMap::new();
+        for ((t, s), e) in self.iter() {
+            by_type.entry(t).or_default().insert(s, e);
+        }
+
+        by_type.serialize(serializer)
+    }
+}
+
+/// Deserializes from either `{type: {state_key: value}}` or a list of
+/// `[[type, state_key], value]` pairs.
+impl<'de, E> Deserialize<'de> for StateMap<E>
+where
+    E: Debug + Clone + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<StateMap<E>, D::Error> {
+        deserializer.deserialize_any(StateMapVisitor(PhantomData))
+    }
+}
+
+struct StateMapVisitor<E>(PhantomData<E>);
+
+impl<'de, E> Visitor<'de> for StateMapVisitor<E>
+where
+    E: Debug + Clone + Deserialize<'de>,
+{
+    type Value = StateMap<E>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map of type to state key to value, or a list of pairs")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<StateMap<E>, A::Error> {
+        let mut state_map = StateMap::new();
+
+        while let Some((t, entries)) = map.next_entry::<String, HashMap<String, E>>()? {
+            for (s, e) in entries {
+                state_map.insert(&t, &s, e);
+            }
+        }
+
+        Ok(state_map)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<StateMap<E>, A::Error> {
+        let mut state_map = StateMap::new();
+
+        while let Some(((t, s), e)) = seq.next_element::<((String, String), E)>()? {
+            state_map.insert(&t, &s, e);
+        }
+
+        Ok(state_map)
+    }
+}
+
 #[test]
 fn add_or_remove_test() {
     let mut state_map = StateMap::new();
@@ -400,15 +593,67 @@ fn add_or_remove_test() {
     ] {
         state_map.insert(t, s, 1);
 
-        let res = state_map.add_or_remove(t, s, 2);
-        assert_eq!(res, Some((2, 1)));
+        let res = state_map.add_or_remove(t, s, &2);
+        assert_eq!(res, Some(1));
 
         assert_eq!(state_map.get(t, s), None);
 
-        let res = state_map.add_or_remove(t, s, 1);
+        let res = state_map.add_or_remove(t, s, &1);
         assert_eq!(res, None);
 
-        let res = state_map.add_or_remove(t, s, 1);
+        let res = state_map.add_or_remove(t, s, &1);
         assert_eq!(res, None);
     }
 }
+
+#[test]
+fn test_serde() {
+    use serde_json;
+
+    let mut state_map = StateMap::new();
+    state_map.insert(TYPE_CREATE, "", 1);
+    state_map.insert(TYPE_MEMBERSHIP, "@a:a", 2);
+    state_map.insert(TYPE_MEMBERSHIP, "@b:a", 3);
+    state_map.insert("m.custom", "foo", 4);
+
+    let json = serde_json::to_string(&state_map).unwrap();
+    assert_eq!(
+        json,
+        r#"{"m.custom":{"foo":4},"m.room.create":{"":1},"m.room.member":{"@a:a":2,"@b:a":3}}"#
+    );
+
+    let parsed: StateMap<u32> = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed.values().count(), 4);
+    assert_eq!(parsed.get(TYPE_MEMBERSHIP, "@b:a"), Some(&3));
+
+    let parsed: StateMap<u32> =
+        serde_json::from_str(r#"[[["m.room.create", ""], 1], [["m.custom", "foo"], 4]]"#).unwrap();
+    assert_eq!(parsed.get_well_known(WellKnownEmptyKeys::Create), Some(&1));
+    assert_eq!(parsed.get("m.custom", "foo"), Some(&4));
+}
+
+#[test]
+fn test_diff() {
+    let old: StateMap<u32> = vec![
+        ((TYPE_CREATE, ""), 1),
+        ((TYPE_MEMBERSHIP, "@a:a"), 2),
+        ((TYPE_MEMBERSHIP, "@b:a"), 3),
+    ]
+    .into_iter()
+    .collect();
+
+    let mut new = old.clone();
+    new.insert(TYPE_MEMBERSHIP, "@a:a", 4);
+    new.remove(TYPE_MEMBERSHIP, "@b:a");
+    new.insert(TYPE_TOPIC, "", 5);
+
+    let delta = old.diff(&new);
+    assert_eq!(delta.added, vec![((TYPE_TOPIC.into(), "".into()), 5)]);
+    assert_eq!(delta.removed, vec![((TYPE_MEMBERSHIP.into(), "@b:a".into()), 3)]);
+    assert_eq!(delta.changed, vec![((TYPE_MEMBERSHIP.into(), "@a:a".into()), 2, 4)]);
+
+    let mut patched = old.clone();
+    patched.apply(&delta);
+    assert!(patched.diff(&new).is_empty());
+    assert!(!old.diff(&patched).is_empty());
+}